@@ -0,0 +1,857 @@
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use indicatif::ProgressBar;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{error::ChatotError, log};
+
+/// Decrypted entry of the message table that follows the archive header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageTableEntry {
+    /// Byte offset of the message from the start of the archive
+    pub offset: u32,
+    /// Message length in u16 codes
+    pub length: u32,
+}
+
+/// Size in bytes of an entry of the message table in an archive, an offset and a length.
+pub const TABLE_ENTRY_SIZE: usize = 8;
+
+// Only the constant defines the archive layout, this just catches a struct that no longer matches
+const _: () = assert!(size_of::<MessageTableEntry>() == TABLE_ENTRY_SIZE);
+
+/// Byte order of the values stored in a binary text archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Endian {
+    /// Used by the retail Nintendo DS games
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn read_u16<R: Read>(self, reader: &mut R) -> std::io::Result<u16> {
+        match self {
+            Endian::Little => reader.read_u16::<LittleEndian>(),
+            Endian::Big => reader.read_u16::<BigEndian>(),
+        }
+    }
+
+    pub fn read_u32<R: Read>(self, reader: &mut R) -> std::io::Result<u32> {
+        match self {
+            Endian::Little => reader.read_u32::<LittleEndian>(),
+            Endian::Big => reader.read_u32::<BigEndian>(),
+        }
+    }
+
+    pub fn write_u16<W: Write>(self, writer: &mut W, value: u16) -> std::io::Result<()> {
+        match self {
+            Endian::Little => writer.write_u16::<LittleEndian>(value),
+            Endian::Big => writer.write_u16::<BigEndian>(value),
+        }
+    }
+
+    pub fn write_u32<W: Write>(self, writer: &mut W, value: u32) -> std::io::Result<()> {
+        match self {
+            Endian::Little => writer.write_u32::<LittleEndian>(value),
+            Endian::Big => writer.write_u32::<BigEndian>(value),
+        }
+    }
+}
+
+/// Pack archives into one container, which starts with an index of the archive count as a u32
+/// followed by the offset of each archive from the start of the container as a u32. The
+/// archives follow the index back to back in order, so each one ends where the next begins.
+pub fn pack_container(archives: &[Vec<u8>], endian: Endian) -> Result<Vec<u8>, ChatotError> {
+    let index_len = 4 + archives.len() * 4;
+    let size = index_len + archives.iter().map(Vec::len).sum::<usize>();
+    if u32::try_from(size).is_err() {
+        return Err(ChatotError::ContainerTooLarge { size: size as u64 });
+    }
+
+    let mut container = Vec::with_capacity(size);
+    endian.write_u32(&mut container, archives.len() as u32)?;
+    let mut offset = index_len;
+    for archive in archives {
+        endian.write_u32(&mut container, offset as u32)?;
+        offset += archive.len();
+    }
+    for archive in archives {
+        container.extend_from_slice(archive);
+    }
+    Ok(container)
+}
+
+/// Split a container written by [`pack_container`] into its archives. Every offset must lie
+/// between the end of the index and the end of the container, and no archive may start before
+/// the one in front of it.
+pub fn unpack_container(container: &[u8], endian: Endian) -> Result<Vec<&[u8]>, ChatotError> {
+    let mut reader = container;
+    let count = endian.read_u32(&mut reader)?;
+    let index_len = 4 + count as u64 * 4;
+    if index_len > container.len() as u64 {
+        return Err(ChatotError::TruncatedContainer {
+            count,
+            container_len: container.len() as u64,
+        });
+    }
+
+    let mut offsets = Vec::with_capacity(count as usize);
+    for index in 0..count as usize {
+        let offset = endian.read_u32(&mut reader)?;
+        let invalid = |reason| {
+            Err(ChatotError::InvalidContainer {
+                index,
+                offset,
+                reason,
+            })
+        };
+        if (offset as u64) < index_len {
+            return invalid("archive starts inside the index");
+        }
+        if offset as usize > container.len() {
+            return invalid("archive starts past the end of the container");
+        }
+        if offsets.last().is_some_and(|&previous| offset < previous) {
+            return invalid("archive starts before the previous archive");
+        }
+        offsets.push(offset);
+    }
+
+    let ends = offsets
+        .iter()
+        .skip(1)
+        .map(|&offset| offset as usize)
+        .chain(std::iter::once(container.len()));
+    Ok(offsets
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| &container[start as usize..end])
+        .collect())
+}
+
+/// Counts printed with `--stats` after encoding or decoding a batch, summed over all files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchStats {
+    pub processed: usize,
+    /// Files skipped by `--newer` because their output was up to date
+    pub skipped: usize,
+    pub messages: usize,
+    /// Size of the archive data read or written, before any compression
+    pub bytes: u64,
+    /// Null codes encoded in place of text that couldn't be encoded
+    pub null_codes: usize,
+}
+
+impl std::ops::AddAssign for BatchStats {
+    fn add_assign(&mut self, other: BatchStats) {
+        self.processed += other.processed;
+        self.skipped += other.skipped;
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+        self.null_codes += other.null_codes;
+    }
+}
+
+impl BatchStats {
+    pub fn print(&self, elapsed: std::time::Duration) {
+        let null_codes = if self.null_codes > 0 {
+            format!(", {} null code(s) inserted", self.null_codes)
+        } else {
+            String::new()
+        };
+        crate::log::info!(
+            "Processed {} file(s), skipped {}, {} message(s), {} archive byte(s){} in {:.2?}",
+            self.processed,
+            self.skipped,
+            self.messages,
+            self.bytes,
+            null_codes,
+            elapsed
+        );
+    }
+}
+
+/// Bar showing the number of processed files with `--progress`, hidden otherwise. Batches count
+/// it with `progress_with` after the closure of their parallel map, so it advances as files
+/// finish rather than as they start.
+pub fn progress_bar(len: usize, enabled: bool) -> ProgressBar {
+    if enabled {
+        ProgressBar::new(len as u64)
+    } else {
+        ProgressBar::hidden()
+    }
+}
+
+/// Which files of a source directory are read, by extension and by name patterns. The default
+/// keeps every file.
+#[derive(Default)]
+pub struct FileFilter<'a> {
+    /// Keep only names ending in `.<ext>`, ignoring case
+    pub ext: Option<&'a str>,
+    /// Keep only names matching at least one of these patterns, unless it's empty
+    pub include: &'a [String],
+    /// Leave out names matching any of these patterns
+    pub exclude: &'a [String],
+}
+
+impl<'a> FileFilter<'a> {
+    pub fn from_settings(settings: &'a crate::Settings) -> Self {
+        FileFilter {
+            ext: settings.ext.as_deref(),
+            include: &settings.include,
+            exclude: &settings.exclude,
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.ext.is_some() || !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let has_ext = self.ext.is_none_or(|ext| {
+            let suffix = format!(".{}", ext.trim_start_matches('.').to_lowercase());
+            name.to_lowercase().ends_with(&suffix)
+        });
+        has_ext
+            && (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, name)))
+            && !self.exclude.iter().any(|p| glob_match(p, name))
+    }
+}
+
+/// Match a file name against a pattern where `*` stands for any run of characters and `?` for
+/// any single one. Everything else matches only itself, including case.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it currently stands in for
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&ch) if ch == '?' || ch == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // Let the last `*` take one more character and try again from there
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+/// List the files in a directory, sorted since read_dir order depends on the filesystem.
+/// Subdirectories and hidden files like `.DS_Store` are left out, unless `recursive` is set, which
+/// lists the files of every subdirectory that isn't hidden as well. Only file names the filter
+/// matches are kept, subdirectories are read whatever their name. A directory without any
+/// matching files is usually a wrong path, so it's a warning or, with `strict`, an error.
+pub fn read_dir_sorted(
+    dir: &Path,
+    filter: &FileFilter,
+    recursive: bool,
+    strict: bool,
+) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = std::fs::read_dir(&current)
+            .map_err(|e| format!("Failed to read directory {:?}: {}", current, e))?;
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                if recursive {
+                    pending.push(path);
+                }
+            } else if filter.matches(name) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+
+    if files.is_empty() {
+        let what = if filter.is_set() {
+            "matching files"
+        } else {
+            "files"
+        };
+        if strict {
+            return Err(format!("Directory {:?} has no {}", dir, what));
+        }
+        log::warning!("Directory {:?} has no {}", dir, what);
+    }
+    Ok(files)
+}
+
+/// Directory to write the output of `path` to. This is `out_dir` extended by the subdirectories
+/// that `path` is in below `source_dir`, so recursively read directories keep their structure.
+pub fn output_dir(out_dir: &Path, path: &Path, source_dir: Option<&Path>) -> PathBuf {
+    let relative = source_dir
+        .and_then(|source_dir| path.parent()?.strip_prefix(source_dir).ok())
+        .unwrap_or(Path::new(""));
+    out_dir.join(relative)
+}
+
+/// Placeholders `--name-pattern` replaces.
+const NAME_PLACEHOLDERS: [&str; 4] = ["stem", "ext", "lang", "index"];
+
+/// Split a name pattern into literal text and placeholders, where `{{` and `}}` stand for
+/// literal braces.
+fn name_pattern_parts(pattern: &str) -> Result<Vec<(bool, String)>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let Some((name, rest)) = chars.as_str().split_once('}') else {
+                    return Err("'{' is never closed".to_string());
+                };
+                if !NAME_PLACEHOLDERS.contains(&name) {
+                    return Err(format!(
+                        "unknown placeholder '{{{}}}', expected one of {{{}}}",
+                        name,
+                        NAME_PLACEHOLDERS.join("}, {")
+                    ));
+                }
+                parts.push((false, std::mem::take(&mut literal)));
+                parts.push((true, name.to_string()));
+                chars = rest.chars();
+            }
+            '}' => return Err("'}' without a matching '{'".to_string()),
+            _ => literal.push(ch),
+        }
+    }
+    parts.push((false, literal));
+    Ok(parts)
+}
+
+/// Parse the value of `--name-pattern`, rejecting unknown placeholders and patterns that
+/// aren't a single file name.
+pub fn parse_name_pattern(value: &str) -> Result<String, String> {
+    let parts = name_pattern_parts(value)?;
+    if parts.iter().all(|(_, text)| text.is_empty()) {
+        return Err("pattern can't be empty".to_string());
+    }
+    if parts
+        .iter()
+        .any(|(placeholder, text)| !placeholder && text.contains(['/', '\\']))
+    {
+        return Err("pattern names a file, so it can't contain path separators".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// Name of the output file for the source file at `index` of a batch, expanding the
+/// placeholders of a pattern read by [`parse_name_pattern`].
+pub fn expand_name_pattern(pattern: &str, path: &Path, lang: &str, index: usize) -> String {
+    let part = |part: Option<&std::ffi::OsStr>| {
+        part.and_then(|part| part.to_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    // Validated when the pattern was parsed
+    let parts = name_pattern_parts(pattern).unwrap_or_default();
+    parts
+        .into_iter()
+        .map(|(placeholder, text)| match (placeholder, text.as_str()) {
+            (false, _) => text,
+            (true, "stem") => part(path.file_stem()),
+            (true, "ext") => part(path.extension()),
+            (true, "lang") => lang.to_string(),
+            (true, _) => index.to_string(),
+        })
+        .collect()
+}
+
+/// Fail if two source files would be written to the same output, which a name pattern without
+/// `{stem}` or `{index}` easily does.
+pub fn check_unique_outputs(inputs: &[PathBuf], outputs: &[PathBuf]) -> Result<(), String> {
+    let mut seen = std::collections::HashMap::new();
+    for (input, output) in inputs.iter().zip(outputs) {
+        if let Some(other) = seen.insert(output, input) {
+            return Err(format!(
+                "Both {:?} and {:?} would be written to {:?}",
+                other, input, output
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Create the directory an output file is written to, along with any missing parents.
+pub fn create_parent_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Whether an archive path names a gzip compressed archive.
+pub fn is_gzip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("gz"))
+}
+
+/// Read an archive file, decompressing it first if the path ends in `.gz`.
+pub fn read_archive_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if !is_gzip(path) {
+        return Ok(data);
+    }
+
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// Write an archive file, compressing it if the path ends in `.gz`.
+pub fn write_archive_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if !is_gzip(path) {
+        return write_file_atomic(path, data);
+    }
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    write_file_atomic(path, &encoder.finish()?)
+}
+
+/// Set the modified time of `to` to the modified time of `from`.
+pub fn copy_modified_time(from: &Path, to: &Path) -> std::io::Result<()> {
+    let modified_time = std::fs::metadata(from)?.modified()?;
+    std::fs::File::open(to)?.set_modified(modified_time)
+}
+
+/// Character encoding of plain text and CSV files, selected with `--encoding`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextEncoding {
+    #[default]
+    #[value(name = "utf-8", alias = "utf8")]
+    Utf8,
+    /// ISO-8859-1, where every byte is the character of the same code point
+    #[value(name = "latin1", alias = "iso-8859-1")]
+    Latin1,
+    #[value(name = "utf-16le")]
+    Utf16Le,
+    #[value(name = "utf-16be")]
+    Utf16Be,
+}
+
+/// Multi-byte legacy encodings that `--encoding` rejects by name, spelled in lowercase with `-`.
+const UNSUPPORTED_ENCODINGS: [&str; 10] = [
+    "shift-jis",
+    "sjis",
+    "cp932",
+    "windows-31j",
+    "euc-jp",
+    "iso-2022-jp",
+    "gbk",
+    "gb2312",
+    "big5",
+    "euc-kr",
+];
+
+/// Parser of `--encoding` that tells multi-byte legacy encodings like Shift-JIS apart from
+/// mistyped names, since only the encodings of [`TextEncoding`] can be read and written.
+#[derive(Clone, Copy, Debug)]
+pub struct TextEncodingParser;
+
+impl clap::builder::TypedValueParser for TextEncodingParser {
+    type Value = TextEncoding;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<TextEncoding, clap::Error> {
+        if let Some(name) = value.to_str()
+            && UNSUPPORTED_ENCODINGS.contains(&name.to_lowercase().replace('_', "-").as_str())
+        {
+            let arg = arg.map_or_else(|| "--encoding".to_string(), ToString::to_string);
+            return Err(cmd.clone().error(
+                clap::error::ErrorKind::InvalidValue,
+                format!(
+                    "invalid value '{name}' for '{arg}': multi-byte legacy encodings like Shift-JIS aren't supported, convert the files to UTF-8 first"
+                ),
+            ));
+        }
+        clap::builder::EnumValueParser::<TextEncoding>::new().parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(
+        &self,
+    ) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue> + '_>> {
+        use clap::ValueEnum;
+        Some(Box::new(
+            TextEncoding::value_variants()
+                .iter()
+                .filter_map(TextEncoding::to_possible_value),
+        ))
+    }
+}
+
+impl TextEncoding {
+    /// Read text in this encoding. A byte order mark at the start of UTF-16 is dropped.
+    pub fn decode(self, bytes: &[u8]) -> Result<String, String> {
+        let utf16 = |to_unit: fn([u8; 2]) -> u16| {
+            if !bytes.len().is_multiple_of(2) {
+                return Err("UTF-16 text has an odd number of bytes".to_string());
+            }
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|pair| to_unit([pair[0], pair[1]]))
+                .collect();
+            let text = String::from_utf16(&units).map_err(|e| e.to_string())?;
+            Ok(text
+                .strip_prefix('\u{FEFF}')
+                .map(str::to_string)
+                .unwrap_or(text))
+        };
+        match self {
+            TextEncoding::Utf8 => String::from_utf8(bytes.to_vec())
+                .map_err(|e| format!("{}, use --encoding for text in another encoding", e)),
+            TextEncoding::Latin1 => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+            TextEncoding::Utf16Le => utf16(u16::from_le_bytes),
+            TextEncoding::Utf16Be => utf16(u16::from_be_bytes),
+        }
+    }
+
+    /// Write text in this encoding. UTF-16 starts with a byte order mark, like editors expect.
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            TextEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            TextEncoding::Latin1 => text
+                .chars()
+                .map(|ch| {
+                    u8::try_from(ch).map_err(|_| format!("'{}' can't be written in Latin-1", ch))
+                })
+                .collect(),
+            TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+                let big_endian = self == TextEncoding::Utf16Be;
+                Ok(std::iter::once(0xFEFF)
+                    .chain(text.encode_utf16())
+                    .flat_map(|unit| {
+                        if big_endian {
+                            unit.to_be_bytes()
+                        } else {
+                            unit.to_le_bytes()
+                        }
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Read a text file in the given encoding.
+pub fn read_text_file(path: &Path, encoding: TextEncoding) -> std::io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    encoding
+        .decode(&bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Write a text file in the given encoding through [`write_file_atomic`].
+pub fn write_text_file(path: &Path, text: &str, encoding: TextEncoding) -> std::io::Result<()> {
+    let bytes = encoding
+        .encode(text)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    write_file_atomic(path, &bytes)
+}
+
+/// Write a file through a temporary file in the same directory that is renamed into place once
+/// it is complete, so an interrupted write never leaves a truncated file behind. The temporary
+/// file is removed again if anything fails.
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let Some(file_name) = path.file_name() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "path has no file name",
+        ));
+    };
+    // Hidden and unique per process, since several files may be written in parallel
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = std::fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        // Replaces an existing file on both Unix and Windows
+        .and_then(|_| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// Empty directory under the system temp directory for tests working with files.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chatot-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_dir_sorted_lists_files_by_name() {
+        let dir = test_dir("read-dir-sorted");
+        for name in ["c.txt", "a.txt", ".DS_Store", "b.txt"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("0.txt"), "").unwrap();
+
+        let files = read_dir_sorted(&dir, &FileFilter::default(), false, true).unwrap();
+        let expected: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        assert_eq!(files, expected);
+
+        let files = read_dir_sorted(&dir, &FileFilter::default(), true, true).unwrap();
+        assert_eq!(files.len(), 4);
+        assert!(files.contains(&dir.join("sub").join("0.txt")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ext_filter_keeps_only_matching_files() {
+        let dir = test_dir("ext-filter");
+        for name in ["a.bin", "b.txt", "C.BIN", "README.md", ".DS_Store", "bin"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        std::fs::create_dir(dir.join("sub.bin")).unwrap();
+
+        for ext in ["bin", ".bin"] {
+            let filter = FileFilter {
+                ext: Some(ext),
+                ..Default::default()
+            };
+            let files = read_dir_sorted(&dir, &filter, false, true).unwrap();
+            assert_eq!(files, [dir.join("C.BIN"), dir.join("a.bin")], "{ext}");
+        }
+        let filter = FileFilter {
+            ext: Some("txt"),
+            ..Default::default()
+        };
+        let files = read_dir_sorted(&dir, &filter, false, true).unwrap();
+        assert_eq!(files, [dir.join("b.txt")]);
+
+        let filter = FileFilter {
+            ext: Some("json"),
+            ..Default::default()
+        };
+        let error = read_dir_sorted(&dir, &filter, false, true).unwrap_err();
+        assert!(error.ends_with("has no matching files"), "{error}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        for (pattern, name, expected) in [
+            ("*", "", true),
+            ("*", "msg.bin", true),
+            ("*.bin", "msg.bin", true),
+            ("*.bin", "msg.bin.bak", false),
+            ("msg_??.bin", "msg_01.bin", true),
+            ("msg_??.bin", "msg_1.bin", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXcYb", false),
+            ("*.BIN", "msg.bin", false),
+            ("msg", "msg", true),
+            ("", "msg", false),
+        ] {
+            assert_eq!(glob_match(pattern, name), expected, "{pattern} {name}");
+        }
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let include = vec!["msg_*".to_string(), "*.bin".to_string()];
+        let exclude = vec!["*_old*".to_string()];
+        let filter = FileFilter {
+            ext: Some("bin"),
+            include: &include,
+            exclude: &exclude,
+        };
+        assert!(filter.matches("msg_01.bin"));
+        assert!(filter.matches("other.bin"));
+        assert!(!filter.matches("msg_01.txt"));
+        assert!(!filter.matches("msg_old.bin"));
+        assert!(!filter.matches("other_old.bin"));
+        assert!(filter.is_set());
+        assert!(!FileFilter::default().is_set());
+    }
+
+    #[test]
+    fn container_round_trip() {
+        let charmap = crate::charmap::get_default_charmap();
+        let archives: Vec<Vec<u8>> = [vec!["Hello"], vec![], vec!["a", "b"]]
+            .iter()
+            .enumerate()
+            .map(|(key, messages)| {
+                let messages: Vec<String> = messages.iter().map(|m| m.to_string()).collect();
+                crate::encode::encode_archive(
+                    charmap,
+                    key as u16,
+                    &messages,
+                    crate::encode::EncodeOptions::default(),
+                )
+                .unwrap()
+            })
+            .collect();
+
+        for endian in [Endian::Little, Endian::Big] {
+            let container = pack_container(&archives, endian).unwrap();
+            let index_len = 4 + 3 * 4;
+            assert_eq!(
+                container.len(),
+                index_len + archives.iter().map(Vec::len).sum::<usize>()
+            );
+            let unpacked = unpack_container(&container, endian).unwrap();
+            assert_eq!(unpacked, archives, "{endian:?}");
+        }
+        assert!(
+            unpack_container(
+                &pack_container(&[], Endian::Little).unwrap(),
+                Endian::Little
+            )
+            .unwrap()
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn broken_container_index_is_rejected() {
+        let reason = |container: &[u8]| match unpack_container(container, Endian::Little) {
+            Err(ChatotError::InvalidContainer { reason, .. }) => reason,
+            Err(ChatotError::TruncatedContainer { .. }) => "truncated",
+            Err(other) => panic!("expected an invalid container, got {other}"),
+            Ok(_) => panic!("{container:?} was unpacked"),
+        };
+        assert_eq!(reason(&[2, 0, 0, 0, 12, 0, 0, 0]), "truncated");
+        assert_eq!(
+            reason(&[1, 0, 0, 0, 4, 0, 0, 0]),
+            "archive starts inside the index"
+        );
+        assert_eq!(
+            reason(&[1, 0, 0, 0, 9, 0, 0, 0]),
+            "archive starts past the end of the container"
+        );
+        assert_eq!(
+            reason(&[2, 0, 0, 0, 13, 0, 0, 0, 12, 0, 0, 0, 0]),
+            "archive starts before the previous archive"
+        );
+    }
+
+    #[test]
+    fn text_files_round_trip_in_every_encoding() {
+        let dir = test_dir("text-encoding");
+        let text = "// Key: 0x1234\nCaf\u{E9} \u{FC}ber\n";
+        for (encoding, start) in [
+            (TextEncoding::Utf8, &b"// "[..]),
+            (TextEncoding::Latin1, b"// "),
+            (TextEncoding::Utf16Le, &[0xFF, 0xFE, b'/', 0]),
+            (TextEncoding::Utf16Be, &[0xFE, 0xFF, 0, b'/']),
+        ] {
+            let path = dir.join(format!("{encoding:?}.txt"));
+            write_text_file(&path, text, encoding).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            assert!(bytes.starts_with(start), "{encoding:?}: {bytes:?}");
+            assert_eq!(
+                read_text_file(&path, encoding).unwrap(),
+                text,
+                "{encoding:?}"
+            );
+        }
+        // Latin-1 stores é as its one byte code point
+        let latin1 = std::fs::read(dir.join("Latin1.txt")).unwrap();
+        assert_eq!(latin1.len(), text.chars().count());
+        assert!(latin1.contains(&0xE9));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn text_encodings_reject_what_they_cant_hold() {
+        assert_eq!(
+            TextEncoding::Latin1.encode("a\u{2026}").unwrap_err(),
+            "'\u{2026}' can't be written in Latin-1"
+        );
+        assert!(TextEncoding::Utf16Le.decode(&[0xFF, 0xFE, b'a']).is_err());
+        // An unpaired surrogate
+        assert!(TextEncoding::Utf16Le.decode(&[0x00, 0xD8]).is_err());
+        assert!(TextEncoding::Utf8.decode(&[0xE9]).is_err());
+        // Without a byte order mark UTF-16 is read as given
+        assert_eq!(TextEncoding::Utf16Be.decode(&[0, b'a']).unwrap(), "a");
+    }
+
+    #[test]
+    fn encoding_option_names_legacy_encodings_as_unsupported() {
+        let cmd = || {
+            clap::Command::new("chatot").arg(
+                clap::Arg::new("encoding")
+                    .long("encoding")
+                    .value_parser(TextEncodingParser),
+            )
+        };
+        let matches = cmd()
+            .try_get_matches_from(["chatot", "--encoding", "iso-8859-1"])
+            .unwrap();
+        assert_eq!(
+            matches.get_one::<TextEncoding>("encoding"),
+            Some(&TextEncoding::Latin1)
+        );
+
+        for name in ["shift_jis", "Shift-JIS", "sjis"] {
+            let error = cmd()
+                .try_get_matches_from(["chatot", "--encoding", name])
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("aren't supported"), "{error}");
+        }
+        let error = cmd()
+            .try_get_matches_from(["chatot", "--encoding", "utf-7"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("possible values: utf-8, latin1"), "{error}");
+    }
+}
@@ -3,16 +3,119 @@ use std::path::PathBuf;
 
 use serde_derive::Deserialize;
 
+use crate::{crypto::CryptoParams, error::ChatotError, log};
+
+#[derive(Clone)]
 pub struct Charmap {
     pub encode_map: HashMap<String, u16>,
     pub decode_map: HashMap<u16, String>,
     pub command_map: HashMap<u16, String>,
+    pub control_codes: ControlCodes,
+    pub crypto: CryptoParams,
+    /// Command name prefixes of the commands whose code carries a special byte
+    pub special_byte_prefixes: Vec<String>,
+    /// Length in chars of the longest multi-character entry outside of aliases and escapes
+    pub longest_sequence: usize,
+}
+
+/// Characters starting aliases, escapes and commands in text, which are never matched greedily.
+const SYNTAX_CHARS: [char; 3] = ['[', '\\', '{'];
+
+impl Charmap {
+    /// Find the longest multi-character entry at the start of `text`, like a character written
+    /// with a combining accent or a ligature spelled out as "ff". Entries starting with `[`, `\`
+    /// or `{` are left to the alias, escape and command handling. Returns the byte length of the
+    /// match and its code.
+    pub fn longest_match(&self, text: &str) -> Option<(usize, u16)> {
+        if self.longest_sequence < 2 || text.starts_with(SYNTAX_CHARS) {
+            return None;
+        }
+
+        let ends: Vec<usize> = text
+            .char_indices()
+            .map(|(start, ch)| start + ch.len_utf8())
+            .take(self.longest_sequence)
+            .collect();
+
+        ends.iter()
+            .skip(1)
+            .rev()
+            .find_map(|&end| self.encode_map.get(&text[..end]).map(|code| (end, *code)))
+    }
+
+    /// Whether the low byte of a command's code is a special byte passed along with the command
+    /// rather than part of the code, which are the commands starting with one of
+    /// `special_byte_prefixes`. Encoding and decoding both follow this, so commands round-trip
+    /// in either text format.
+    pub fn has_special_byte(&self, command_name: &str) -> bool {
+        self.special_byte_prefixes
+            .iter()
+            .any(|prefix| command_name.starts_with(prefix.as_str()))
+    }
+
+    /// Codes and names of the command map sorted by code.
+    pub fn sorted_commands(&self) -> Vec<(u16, &str)> {
+        let mut commands: Vec<(u16, &str)> = self
+            .command_map
+            .iter()
+            .map(|(code, name)| (*code, name.as_str()))
+            .collect();
+        commands.sort_unstable();
+        commands
+    }
+}
+
+/// Codes with a special meaning inside a message rather than mapping to a character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlCodes {
+    /// Ends a message
+    pub terminator: u16,
+    /// Starts a command followed by the command code, parameter count and parameters
+    pub command_marker: u16,
+    /// Starts a 9-bit packed trainer name
+    pub trainer_name: u16,
+}
+
+impl Default for ControlCodes {
+    fn default() -> Self {
+        ControlCodes {
+            terminator: 0xFFFF,
+            command_marker: 0xFFFE,
+            trainer_name: 0xF100,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct RawCharmap {
     char_map: HashMap<String, RawCharEntry>,
     command_map: HashMap<String, String>,
+    #[serde(default)]
+    control_codes: RawControlCodes,
+    #[serde(default)]
+    crypto: RawCrypto,
+    #[serde(default)]
+    special_byte_prefixes: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawCrypto {
+    #[serde(default)]
+    table_seed: Option<u32>,
+    #[serde(default)]
+    msg_mul: Option<u32>,
+    #[serde(default)]
+    msg_add: Option<u16>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawControlCodes {
+    #[serde(default)]
+    terminator: Option<String>,
+    #[serde(default)]
+    command_marker: Option<String>,
+    #[serde(default)]
+    trainer_name: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -23,28 +126,162 @@ struct RawCharEntry {
     aliases: Option<Vec<String>>,
 }
 
-pub fn read_charmap(path: &PathBuf) -> Result<Charmap, Box<dyn std::error::Error>> {
+/// Read a charmap file like [`read_charmap`], but fail if any code can't round-trip: codes whose
+/// character encodes to another code, and codes that can be encoded but not decoded because they
+/// only have aliases. Every such code is printed as a warning first, as well as command codes
+/// that are also used for a character.
+pub fn read_charmap_strict(path: &PathBuf) -> Result<Charmap, ChatotError> {
+    let charmap = read_charmap(path)?;
+
+    let mut not_encodable: Vec<(u16, &String)> = charmap
+        .decode_map
+        .iter()
+        .filter(|(code, ch)| charmap.encode_map.get(*ch) != Some(code))
+        .map(|(code, ch)| (*code, ch))
+        .collect();
+    not_encodable.sort();
+    for (code, ch) in &not_encodable {
+        log::warning!(
+            "code {code:04X} decodes to '{ch}', which encodes to code {:04X}",
+            charmap.encode_map[*ch]
+        );
+    }
+
+    let mut not_decodable: Vec<(u16, &String)> = charmap
+        .encode_map
+        .iter()
+        .filter(|(_, code)| !charmap.decode_map.contains_key(code))
+        .map(|(text, code)| (*code, text))
+        .collect();
+    not_decodable.sort();
+    for (code, text) in &not_decodable {
+        log::warning!("'{text}' encodes to code {code:04X}, which has no character to decode to");
+    }
+
+    // Command codes only ever follow a command marker, so sharing them with characters is
+    // unambiguous, but it easily hides a typo in either map
+    let mut shared: Vec<(&u16, &String)> = charmap
+        .command_map
+        .iter()
+        .filter(|(code, _)| charmap.decode_map.contains_key(code))
+        .collect();
+    shared.sort();
+    for (code, name) in shared {
+        log::warning!(
+            "command {name} uses code {code:04X}, which char_map also maps to '{}'",
+            charmap.decode_map[code]
+        );
+    }
+
+    if !not_encodable.is_empty() || !not_decodable.is_empty() {
+        return Err(ChatotError::CharmapParse(format!(
+            "{} code(s) can be decoded but not encoded and {} alias(es) can be encoded but not decoded",
+            not_encodable.len(),
+            not_decodable.len()
+        )));
+    }
+
+    Ok(charmap)
+}
+
+/// Charmaps compiled into the binary, selected with `builtin:<name>` in place of a path.
+const BUILTIN_CHARMAPS: &[(&str, &str)] = &[("gen4", include_str!("../charmap.json"))];
+
+/// Read a charmap file. The format is picked by file extension: `.yaml`/`.yml` and `.toml`
+/// are supported next to JSON, which is also used for any other extension.
+/// A path of the form `builtin:<name>` reads one of the embedded charmaps instead.
+pub fn read_charmap(path: &PathBuf) -> Result<Charmap, ChatotError> {
+    let location = path.to_string_lossy();
+    if let Some(name) = location.strip_prefix("builtin:") {
+        let Some((_, content)) = BUILTIN_CHARMAPS
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+        else {
+            let names: Vec<&str> = BUILTIN_CHARMAPS.iter().map(|(name, _)| *name).collect();
+            return Err(ChatotError::CharmapParse(format!(
+                "Unknown builtin charmap '{}', available: {}",
+                name,
+                names.join(", ")
+            )));
+        };
+        return decode_charmap(content);
+    }
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Err(ChatotError::CharmapParse(format!(
+            "Can't download charmap '{}', save it to a file and pass its path instead",
+            location
+        )));
+    }
+
     let content = std::fs::read_to_string(path)?;
-    decode_charmap(&content)
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    let raw: RawCharmap =
+        match extension.as_deref() {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+                .map_err(|e| ChatotError::CharmapParse(e.to_string()))?,
+            Some("toml") => {
+                toml::from_str(&content).map_err(|e| ChatotError::CharmapParse(e.to_string()))?
+            }
+            _ => serde_json::from_str(&content)
+                .map_err(|e| ChatotError::CharmapParse(e.to_string()))?,
+        };
+
+    build_charmap(raw)
 }
 
-pub fn decode_charmap(content: &str) -> Result<Charmap, Box<dyn std::error::Error>> {
-    let raw: RawCharmap = serde_json::from_str(content)?;
+pub fn decode_charmap(content: &str) -> Result<Charmap, ChatotError> {
+    let raw: RawCharmap =
+        serde_json::from_str(content).map_err(|e| ChatotError::CharmapParse(e.to_string()))?;
 
+    build_charmap(raw)
+}
+
+/// Parse a hex code of the charmap, called `what` in errors. Codes are 16 bits, so a code that
+/// is too large gets its own error naming the limit instead of a generic overflow message.
+fn parse_hex_code(code_str: &str, what: &str) -> Result<u16, ChatotError> {
+    u16::from_str_radix(code_str, 16).map_err(|e| {
+        ChatotError::CharmapParse(match e.kind() {
+            std::num::IntErrorKind::PosOverflow => {
+                format!("{what} '{code_str}' exceeds u16 range (max FFFF)")
+            }
+            _ => format!("Invalid {what} '{code_str}': {e}"),
+        })
+    })
+}
+
+fn build_charmap(raw: RawCharmap) -> Result<Charmap, ChatotError> {
     let mut decode_map = HashMap::with_capacity(raw.char_map.len());
     let mut encode_map = HashMap::with_capacity(raw.char_map.len());
     let mut alias_map = HashMap::new();
 
-    // First pass: build decode and encode maps
+    // Go through the entries by code, so the lowest code wins when a character or alias is
+    // used more than once instead of whichever the map happens to yield first
+    let mut entries = Vec::with_capacity(raw.char_map.len());
     for (code_str, entry) in raw.char_map {
-        let code = u16::from_str_radix(&code_str, 16)
-            .map_err(|e| format!("Invalid char_map key {code_str}: {e}"))?;
+        let code = parse_hex_code(&code_str, "char_map key")?;
+        entries.push((code, entry));
+    }
+    entries.sort_by_key(|(code, _)| *code);
 
-        if let Some(ch) = entry.char {
-            if !ch.is_empty() {
-                decode_map.insert(code, ch.clone());
-                encode_map.entry(ch).or_insert(code);
+    // First pass: build decode and encode maps
+    for (code, entry) in entries {
+        if let Some(ch) = entry.char
+            && !ch.is_empty()
+        {
+            // Keys like "41" and "0041" resolve to the same code, don't let either silently win
+            if let Some(existing) = decode_map.get(&code)
+                && *existing != ch
+            {
+                return Err(ChatotError::CharmapParse(format!(
+                    "Code {code:04X} is mapped to both '{existing}' and '{ch}'"
+                )));
             }
+            decode_map.insert(code, ch.clone());
+            encode_map.entry(ch).or_insert(code);
         }
 
         if let Some(aliases) = entry.aliases {
@@ -58,22 +295,22 @@ pub fn decode_charmap(content: &str) -> Result<Charmap, Box<dyn std::error::Erro
     for (alias, code) in alias_map {
         // Basic alias validation
         if alias.is_empty() {
-            eprint!("Warning: empty alias for code {code:04X} ignored\n");
+            log::warning!("empty alias for code {code:04X} ignored");
             continue;
         }
 
         // Only insert the alias if it doesn't already exist in the encode map
         if encode_map.contains_key(&alias) {
-            eprintln!(
-                "Warning: alias '{alias}' for code {code:04X} conflicts with existing entry, ignored"
+            log::warning!(
+                "alias '{alias}' for code {code:04X} conflicts with existing entry, ignored"
             );
             continue;
         }
 
         // Multi character aliases must be wrapped in square brackets
         if alias.chars().count() > 1 && !(alias.starts_with('[') && alias.ends_with(']')) {
-            eprintln!(
-                "Warning: multi-character alias '{alias}' for code {code:04X} must be wrapped in square brackets, ignored"
+            log::warning!(
+                "multi-character alias '{alias}' for code {code:04X} must be wrapped in square brackets, ignored"
             );
             continue;
         }
@@ -83,21 +320,100 @@ pub fn decode_charmap(content: &str) -> Result<Charmap, Box<dyn std::error::Erro
 
     let mut command_map = HashMap::with_capacity(raw.command_map.len());
     for (code_str, name) in raw.command_map {
-        let code = u16::from_str_radix(&code_str, 16)
-            .map_err(|e| format!("Invalid command_map key {code_str}: {e}"))?;
+        let code = parse_hex_code(&code_str, "command_map key")?;
         command_map.insert(code, name);
     }
 
+    // Control codes not given in the charmap keep the Gen IV values
+    let defaults = ControlCodes::default();
+    let parse_control_code = |name: &str, value: Option<String>, default: u16| match value {
+        Some(code_str) => parse_hex_code(&code_str, &format!("control code {name}")),
+        None => Ok(default),
+    };
+    let control_codes = ControlCodes {
+        terminator: parse_control_code(
+            "terminator",
+            raw.control_codes.terminator,
+            defaults.terminator,
+        )?,
+        command_marker: parse_control_code(
+            "command_marker",
+            raw.control_codes.command_marker,
+            defaults.command_marker,
+        )?,
+        trainer_name: parse_control_code(
+            "trainer_name",
+            raw.control_codes.trainer_name,
+            defaults.trainer_name,
+        )?,
+    };
+
+    // Encryption constants not given in the charmap keep the Gen IV values as well
+    let default_crypto = CryptoParams::default();
+    let crypto = CryptoParams {
+        table_seed: raw.crypto.table_seed.unwrap_or(default_crypto.table_seed),
+        msg_mul: raw.crypto.msg_mul.unwrap_or(default_crypto.msg_mul),
+        msg_add: raw.crypto.msg_add.unwrap_or(default_crypto.msg_add),
+    };
+
+    // Only the STRVAR_ commands of Gen IV carry a special byte
+    let special_byte_prefixes = raw
+        .special_byte_prefixes
+        .unwrap_or_else(|| vec![String::from("STRVAR_")]);
+    if special_byte_prefixes.iter().any(String::is_empty) {
+        return Err(ChatotError::CharmapParse(
+            "Empty special byte prefix would give every command a special byte".to_string(),
+        ));
+    }
+
+    // Control codes are checked before the character map when decoding, so a character there
+    // could never be decoded and would turn into a control code when encoded
+    let reserved = [
+        ("terminator", control_codes.terminator),
+        ("command_marker", control_codes.command_marker),
+        ("trainer_name", control_codes.trainer_name),
+    ];
+    for (name, code) in reserved {
+        let mut texts: Vec<&String> = encode_map
+            .iter()
+            .filter(|(_, text_code)| **text_code == code)
+            .map(|(text, _)| text)
+            .collect();
+        if let Some(ch) = decode_map.get(&code) {
+            texts.push(ch);
+        }
+        texts.sort();
+        texts.dedup();
+        if !texts.is_empty() {
+            let texts: Vec<String> = texts.iter().map(|text| format!("'{text}'")).collect();
+            return Err(ChatotError::CharmapParse(format!(
+                "Code {code:04X} is the {name} control code, but char_map maps {} to it",
+                texts.join(", ")
+            )));
+        }
+    }
+
+    let longest_sequence = encode_map
+        .keys()
+        .filter(|key| !key.starts_with(SYNTAX_CHARS))
+        .map(|key| key.chars().count())
+        .max()
+        .unwrap_or(0);
+
     Ok(Charmap {
         encode_map,
         decode_map,
         command_map,
+        control_codes,
+        crypto,
+        special_byte_prefixes,
+        longest_sequence,
     })
 }
 
 #[allow(dead_code)]
 pub fn default_charmap() -> Charmap {
-    decode_charmap(include_str!("../charmap.json")).expect("Failed to decode embedded charmap")
+    decode_charmap(BUILTIN_CHARMAPS[0].1).expect("Failed to decode embedded charmap")
 }
 
 #[allow(dead_code)]
@@ -105,5 +421,119 @@ static DEFAULT_CHARMAP: std::sync::OnceLock<Charmap> = std::sync::OnceLock::new(
 
 #[allow(dead_code)]
 pub fn get_default_charmap() -> &'static Charmap {
-    DEFAULT_CHARMAP.get_or_init(|| default_charmap())
+    DEFAULT_CHARMAP.get_or_init(default_charmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn charmap_error(content: &str) -> String {
+        match decode_charmap(content) {
+            Err(ChatotError::CharmapParse(message)) => message,
+            Err(other) => panic!("expected a charmap parse error, got {other}"),
+            Ok(_) => panic!("expected a charmap parse error"),
+        }
+    }
+
+    #[test]
+    fn codes_written_differently_must_map_to_the_same_character() {
+        let message = charmap_error(
+            r#"{
+                "char_map": { "41": { "char": "A" }, "0041": { "char": "B" } },
+                "command_map": {}
+            }"#,
+        );
+        assert!(message.contains("0041"), "{message}");
+        assert!(
+            message.contains("'A'") && message.contains("'B'"),
+            "{message}"
+        );
+
+        let charmap = decode_charmap(
+            r#"{
+                "char_map": { "41": { "char": "A" }, "0041": { "char": "A" } },
+                "command_map": {}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(charmap.decode_map[&0x41], "A");
+    }
+
+    #[test]
+    fn characters_on_control_codes_are_rejected() {
+        for (code, name) in [
+            ("FFFF", "terminator"),
+            ("FFFE", "command_marker"),
+            ("F100", "trainer_name"),
+        ] {
+            let message = charmap_error(&format!(
+                r#"{{ "char_map": {{ "{code}": {{ "char": "A" }} }}, "command_map": {{}} }}"#
+            ));
+            assert_eq!(
+                message,
+                format!("Code {code} is the {name} control code, but char_map maps 'A' to it")
+            );
+        }
+
+        // Aliases collide as well, and a moved control code frees its old code
+        let message = charmap_error(
+            r#"{
+                "char_map": { "0100": { "aliases": ["A"] }, "FFFF": { "char": "B" } },
+                "command_map": {},
+                "control_codes": { "terminator": "0100" }
+            }"#,
+        );
+        assert_eq!(
+            message,
+            "Code 0100 is the terminator control code, but char_map maps 'A' to it"
+        );
+    }
+
+    #[test]
+    fn command_codes_may_be_shared_with_characters() {
+        let charmap = decode_charmap(
+            r#"{
+                "char_map": { "0200": { "char": "A" } },
+                "command_map": { "0200": "YESNO" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(charmap.decode_map[&0x0200], "A");
+        assert_eq!(charmap.command_map[&0x0200], "YESNO");
+    }
+
+    #[test]
+    fn map_keys_must_be_16_bit_hex() {
+        for (char_key, command_key, expected) in [
+            (
+                "10000",
+                "0200",
+                "char_map key '10000' exceeds u16 range (max FFFF)",
+            ),
+            (
+                "0041",
+                "1FFFF",
+                "command_map key '1FFFF' exceeds u16 range (max FFFF)",
+            ),
+            (
+                "00G1",
+                "0200",
+                "Invalid char_map key '00G1': invalid digit found in string",
+            ),
+            (
+                "0041",
+                "YESNO",
+                "Invalid command_map key 'YESNO': invalid digit found in string",
+            ),
+        ] {
+            let message = charmap_error(&format!(
+                r#"{{
+                    "char_map": {{ "{char_key}": {{ "char": "A" }} }},
+                    "command_map": {{ "{command_key}": "YESNO" }}
+                }}"#
+            ));
+            assert_eq!(message, expected);
+        }
+    }
 }
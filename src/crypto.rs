@@ -0,0 +1,64 @@
+/// Seed of the message table keys used by the Gen IV games.
+pub const DEFAULT_TABLE_SEED: u32 = 765;
+/// Multiplier deriving the first message key from the message index in the Gen IV games.
+pub const DEFAULT_MSG_MUL: u32 = 596947;
+/// Amount added to the message key after each code in the Gen IV games.
+pub const DEFAULT_MSG_ADD: u16 = 18749;
+
+/// Constants of the archive encryption, which differ between some game builds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CryptoParams {
+    /// Seed of the keys encrypting the message table
+    pub table_seed: u32,
+    /// Multiplier deriving the first key of a message from its index
+    pub msg_mul: u32,
+    /// Amount added to the key after each code of a message
+    pub msg_add: u16,
+}
+
+impl CryptoParams {
+    /// All keys are zero, so encrypting and decrypting leave the data unchanged.
+    pub const PLAINTEXT: CryptoParams = CryptoParams {
+        table_seed: 0,
+        msg_mul: 0,
+        msg_add: 0,
+    };
+}
+
+impl Default for CryptoParams {
+    fn default() -> Self {
+        CryptoParams {
+            table_seed: DEFAULT_TABLE_SEED,
+            msg_mul: DEFAULT_MSG_MUL,
+            msg_add: DEFAULT_MSG_ADD,
+        }
+    }
+}
+
+/// XOR key for the offset and length of the message table entry at `index` (1-based).
+/// Both halves of the u32 carry the same 16-bit key.
+pub fn table_key(key: u16, index: u32, table_seed: u32) -> u32 {
+    let mut local_key: u32 = table_seed;
+    local_key = local_key.wrapping_mul(index);
+    local_key = local_key.wrapping_mul(key as u32);
+    local_key &= 0xFFFF;
+
+    local_key | (local_key << 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_key_wraps_for_large_indices_and_keys() {
+        // The product overflows u32, only its low 16 bits are kept
+        let product = DEFAULT_TABLE_SEED as u64 * 5000 * 0xFFFF;
+        let expected = (product & 0xFFFF) as u32;
+        assert_eq!(expected, 0xA298);
+        assert_eq!(
+            table_key(0xFFFF, 5000, DEFAULT_TABLE_SEED),
+            expected | (expected << 16)
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{decode::TextArchive, encode, error::ChatotError};
+
+/// Write an archive as CSV with an `index,message` header, a `key` row and one row per message.
+/// Fields are quoted as in RFC 4180 when they hold a comma, quote or line break.
+pub(crate) fn archive_to_csv(archive: &TextArchive) -> String {
+    let mut content = String::from("index,message\r\n");
+    content.push_str(&format!("key,0x{:04X}\r\n", archive.key));
+    for (index, message) in archive.messages.iter().enumerate() {
+        content.push_str(&format!("{},{}\r\n", index, quote_field(message)));
+    }
+    content
+}
+
+fn quote_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Read the key and messages from CSV written by [`archive_to_csv`]. Messages are placed by their
+/// index, so rows may be in any order, and gaps are filled with empty messages.
+pub(crate) fn parse_csv_messages(content: &str) -> Result<(u16, Vec<String>), ChatotError> {
+    // Spreadsheet programs often save CSV with a UTF-8 BOM
+    let content = content.trim_start_matches('\u{FEFF}');
+    let mut key = 0u16;
+    let mut placed: Vec<Option<String>> = Vec::new();
+
+    for (line, record) in parse_records(content)? {
+        let invalid = |reason| Err(ChatotError::InvalidCsv { line, reason });
+        let [first, message] = record.as_slice() else {
+            return invalid("expected the two columns index and message");
+        };
+
+        match first.trim() {
+            "index" => continue,
+            "key" => {
+                key = encode::parse_number(message.trim()).map_err(|reason| {
+                    ChatotError::InvalidKey {
+                        key: message.trim().to_string(),
+                        reason,
+                    }
+                })?;
+            }
+            index => {
+                let Ok(index) = index.parse::<usize>() else {
+                    return invalid("index is not a number");
+                };
+                if index > u16::MAX as usize {
+                    return invalid("index is too large for an archive");
+                }
+                if placed.len() <= index {
+                    placed.resize(index + 1, None);
+                }
+                if placed[index].is_some() {
+                    return invalid("index is used by another row");
+                }
+                placed[index] = Some(message.clone());
+            }
+        }
+    }
+
+    Ok((
+        key,
+        placed.into_iter().map(Option::unwrap_or_default).collect(),
+    ))
+}
+
+/// Split CSV into records of fields along with the line each record starts on. CRLF, LF and a
+/// lone CR end a record, line breaks inside quoted fields are kept as they are.
+fn parse_records(content: &str) -> Result<Vec<(usize, Vec<String>)>, ChatotError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = content.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if quoted {
+            match ch {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => {
+                    if ch == '\n' || (ch == '\r' && chars.peek() != Some(&'\n')) {
+                        line += 1;
+                    }
+                    field.push(ch);
+                }
+            }
+            continue;
+        }
+
+        match ch {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' | '\n' => {
+                if ch == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                record.push(std::mem::take(&mut field));
+                // Blank lines hold no record
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push((record_line, std::mem::take(&mut record)));
+                }
+                record.clear();
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(ch),
+        }
+    }
+
+    if quoted {
+        return Err(ChatotError::InvalidCsv {
+            line: record_line,
+            reason: "quoted field is never closed",
+        });
+    }
+    // The last record doesn't need a line break
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push((record_line, record));
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv_error(content: &str) -> (usize, &'static str) {
+        match parse_csv_messages(content) {
+            Err(ChatotError::InvalidCsv { line, reason }) => (line, reason),
+            Err(other) => panic!("expected invalid CSV, got {other}"),
+            Ok(_) => panic!("invalid CSV was parsed"),
+        }
+    }
+
+    #[test]
+    fn quoted_fields_round_trip() {
+        let archive = TextArchive {
+            key: 0xBEEF,
+            messages: vec![
+                "one, two".to_string(),
+                "say \"hi\"".to_string(),
+                "line\nbreak".to_string(),
+                "crlf\r\nbreak".to_string(),
+                String::new(),
+                "plain".to_string(),
+            ],
+        };
+        let content = archive_to_csv(&archive);
+        assert!(content.contains("1,\"say \"\"hi\"\"\"\r\n"));
+
+        let (key, messages) = parse_csv_messages(&content).unwrap();
+        assert_eq!(key, archive.key);
+        assert_eq!(messages, archive.messages);
+    }
+
+    #[test]
+    fn lines_after_a_multi_line_field_are_counted() {
+        let content = "index,message\n0,\"a\nb\r\nc\"\n1,x,y\n";
+        assert_eq!(
+            csv_error(content),
+            (5, "expected the two columns index and message")
+        );
+        assert_eq!(
+            csv_error("0,\"a\rb\"\rbad,x\r"),
+            (3, "index is not a number")
+        );
+    }
+
+    #[test]
+    fn lone_carriage_return_ends_a_record() {
+        let (_, messages) = parse_csv_messages("0,x\r").unwrap();
+        assert_eq!(messages, ["x"]);
+        let (_, messages) = parse_csv_messages("0,x\r1,y\r\n").unwrap();
+        assert_eq!(messages, ["x", "y"]);
+    }
+
+    #[test]
+    fn byte_order_mark_is_skipped() {
+        let (key, messages) =
+            parse_csv_messages("\u{FEFF}index,message\r\nkey,0x0102\r\n0,a\r\n").unwrap();
+        assert_eq!(key, 0x0102);
+        assert_eq!(messages, ["a"]);
+    }
+
+    #[test]
+    fn rows_are_placed_by_index() {
+        let (key, messages) = parse_csv_messages("index,message\n3,d\n0,a\nkey,7\n1,b\n").unwrap();
+        assert_eq!(key, 7);
+        assert_eq!(messages, ["a", "b", "", "d"]);
+
+        assert_eq!(
+            csv_error("index,message\n0,a\n1,b\n0,c\n"),
+            (4, "index is used by another row")
+        );
+        assert_eq!(
+            csv_error("index,message\n65536,a\n"),
+            (2, "index is too large for an archive")
+        );
+        assert_eq!(parse_csv_messages("65535,a\n").unwrap().1.len(), 65536);
+    }
+
+    #[test]
+    fn unclosed_quote_is_rejected() {
+        assert_eq!(
+            csv_error("index,message\n0,a\n1,\"b\n2,c\n"),
+            (3, "quoted field is never closed")
+        );
+    }
+}
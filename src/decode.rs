@@ -1,12 +1,19 @@
-use byteorder::{LittleEndian, ReadBytesExt};
+use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    io::Cursor,
+    collections::{BTreeMap, HashSet},
+    io::{Cursor, Write},
 };
 
-use crate::charmap;
+use crate::{
+    archive::{self, Endian, MessageTableEntry},
+    charmap,
+    crypto::{self, CryptoParams},
+    csv, encode,
+    error::ChatotError,
+    log,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TextArchive {
@@ -14,11 +21,13 @@ pub struct TextArchive {
     pub messages: Vec<String>,
 }
 
+/// Message of a decoded JSON file. The id comes first and the languages follow sorted by code, so
+/// decoding again only changes the lines of messages that actually differ.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct JsonMessage {
     pub id: String,
     #[serde(flatten)]
-    pub lang_message: HashMap<String, MessageContent>,
+    pub lang_message: BTreeMap<String, MessageContent>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -34,176 +43,783 @@ struct JsonOutput {
     messages: Vec<JsonMessage>,
 }
 
-struct MessageTableEntry {
-    offset: u32,
-    length: u32,
-}
-
 pub fn decode_archives(
     charmap: &charmap::Charmap,
     source: &crate::BinarySource,
-    destination: &crate::TextSource,
+    destination: &crate::TextDestination,
     settings: &crate::Settings,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // Run everything inside a dedicated pool when the thread count is capped
+    if let Some(threads) = settings.threads {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+        let settings = crate::Settings {
+            threads: None,
+            ..settings.clone()
+        };
+        pool.install(|| {
+            decode_archives(charmap, source, destination, &settings).map_err(|e| e.to_string())
+        })?;
+        return Ok(());
+    }
+
+    // Plaintext archives use all-zero keys, which turns every XOR into a no-op
+    if settings.no_encrypt && charmap.crypto != CryptoParams::PLAINTEXT {
+        let charmap = charmap::Charmap {
+            crypto: CryptoParams::PLAINTEXT,
+            ..charmap.clone()
+        };
+        return decode_archives(&charmap, source, destination, settings);
+    }
+
+    let start = std::time::Instant::now();
+
+    if let Some(container_path) = &source.container {
+        let Some(dir) = &destination.text_dir else {
+            return Err("Decoding a container requires a text directory destination".into());
+        };
+        let stats = decode_container(charmap, container_path, dir, settings)?;
+        if settings.stats {
+            stats.print(start.elapsed());
+        }
+        return Ok(());
+    }
+
     // Get list of archive files
     let archive_files = if let Some(files) = &source.archive {
         files.clone()
     } else if let Some(dir) = &source.archive_dir {
-        // Read all files from directory
-        std::fs::read_dir(dir)?
-            .filter_map(|entry| entry.ok())
-            .map(|entry| entry.path())
-            .collect()
+        archive::read_dir_sorted(
+            dir,
+            &archive::FileFilter::from_settings(settings),
+            settings.recursive,
+            settings.strict,
+        )?
     } else {
         return Err("No archive source specified".into());
     };
 
+    if destination.stdout {
+        let [archive_path] = archive_files.as_slice() else {
+            return Err("Printing to stdout requires exactly one archive".into());
+        };
+        let (archive, truncated, _) =
+            decode_archive_file(charmap, archive_path, settings.msgenc_format, settings)?;
+        if settings.hexdump {
+            write_hexdump(charmap, archive_path, &archive, None, settings)?;
+        }
+
+        let content = if settings.json {
+            let archive_name = archive_path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("archive");
+            let output = build_json_output(
+                archive_name,
+                archive.key,
+                &[(settings.lang.as_str(), &archive)],
+                BTreeMap::new(),
+            );
+            serde_json::to_string_pretty(&output)? + "\n"
+        } else if settings.csv {
+            csv::archive_to_csv(&archive)
+        } else {
+            decoded_text(&archive, truncated, settings)
+        };
+
+        let content = settings.encoding.encode(&content)?;
+
+        // A reader like `head` closing the pipe early isn't an error
+        let mut stdout = std::io::stdout().lock();
+        return match stdout.write_all(&content).and_then(|_| stdout.flush()) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            result => Ok(result?),
+        };
+    }
+
+    if settings.all_langs {
+        let Some(dir) = &destination.text_dir else {
+            return Err("Decoding all languages requires a text directory destination".into());
+        };
+        let stats = decode_archives_all_langs(
+            charmap,
+            &archive_files,
+            source.archive_dir.as_deref(),
+            dir,
+            settings,
+        )?;
+        if settings.stats {
+            stats.print(start.elapsed());
+        }
+        return Ok(());
+    }
+
+    if let Some(combined_path) = &destination.combine {
+        let stats = decode_archives_combined(
+            charmap,
+            &archive_files,
+            source.archive_dir.as_deref(),
+            combined_path,
+            settings,
+        )?;
+        if settings.stats {
+            stats.print(start.elapsed());
+        }
+        return Ok(());
+    }
+
     // Get list of text files
     let text_files = if let Some(files) = &destination.txt {
         files.clone()
     } else if let Some(dir) = &destination.text_dir {
-        let extension = if settings.json { "json" } else { "txt" };
+        let extension = text_extension(settings);
 
         // Create vector of text file paths which will be created when writing
-        archive_files
+        let text_files: Vec<_> = archive_files
             .iter()
-            .map(|archive_path| {
-                let file_stem = archive_path
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("output");
-                dir.join(format!("{}.{}", file_stem, extension))
+            .enumerate()
+            .map(|(index, archive_path)| {
+                let name = match &settings.name_pattern {
+                    Some(pattern) => {
+                        archive::expand_name_pattern(pattern, archive_path, &settings.lang, index)
+                    }
+                    None => {
+                        let file_stem = archive_path
+                            .file_stem()
+                            .and_then(|s| s.to_str())
+                            .unwrap_or("output");
+                        format!("{}.{}", file_stem, extension)
+                    }
+                };
+                archive::output_dir(dir, archive_path, source.archive_dir.as_deref()).join(name)
             })
-            .collect()
+            .collect();
+        archive::check_unique_outputs(&archive_files, &text_files)?;
+        text_files
     } else {
         return Err("No text destination specified".into());
     };
 
+    // Zipping would silently drop whatever doesn't have a counterpart
+    if archive_files.len() != text_files.len() {
+        return Err(format!(
+            "{} inputs but {} outputs specified",
+            archive_files.len(),
+            text_files.len()
+        )
+        .into());
+    }
+
     // Open and decode each archive in parallel
-    let archive_text_pairs: Vec<_> = archive_files
-        .into_iter()
-        .zip(text_files.into_iter())
-        .collect();
+    let archive_text_pairs: Vec<_> = archive_files.into_iter().zip(text_files).collect();
 
-    let results: Vec<Result<(), String>> = archive_text_pairs
+    let progress = archive::progress_bar(archive_text_pairs.len(), settings.progress);
+
+    let results: Vec<Result<archive::BatchStats, String>> = archive_text_pairs
         .par_iter()
         .map(|(archive_path, text_path)| {
-            // Check newer_only setting is enabled and skip if destination is newer
-            if settings.newer_only {
-                if text_path.exists() && archive_path.exists() {
-                    let archive_metadata = std::fs::metadata(archive_path).map_err(|e| {
-                        format!(
-                            "Failed to get metadata for archive {:?}: {}",
-                            archive_path, e
-                        )
-                    })?;
-                    let text_metadata = std::fs::metadata(text_path).map_err(|e| {
-                        format!(
-                            "Failed to get metadata for text file {:?}: {}",
-                            text_path, e
-                        )
-                    })?;
-                    let archive_modified = archive_metadata.modified().map_err(|e| {
-                        format!(
-                            "Failed to get modified time for archive {:?}: {}",
-                            archive_path, e
-                        )
-                    })?;
-                    let text_modified = text_metadata.modified().map_err(|e| {
-                        format!(
-                            "Failed to get modified time for text file {:?}: {}",
-                            text_path, e
-                        )
-                    })?;
-                    if archive_modified <= text_modified {
-                        #[cfg(debug_assertions)]
-                        println!(
-                            "Skipping decoding of {:?} as destination {:?} is newer",
-                            archive_path, text_path
-                        );
-                        return Ok(());
-                    }
-                }
-            }
-
-            #[cfg(debug_assertions)]
-            println!("Decoding archive: {:?} -> {:?}", archive_path, text_path);
-
-            let archive_file = std::fs::read(archive_path)
-                .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
-            let mut cursor = Cursor::new(&archive_file);
-            let archive = decode_archive(&charmap, &mut cursor, settings.msgenc_format)
-                .map_err(|e| format!("Failed to decode archive {:?}: {}", archive_path, e))?;
-
-            if settings.json {
-                write_decoded_json(&archive, text_path, settings.lang.clone()).map_err(|e| {
-                    format!("Failed to write decoded JSON to {:?}: {}", text_path, e)
-                })?;
-            } else {
-                write_decoded_text(&archive, text_path, settings.msgenc_format).map_err(|e| {
-                    format!("Failed to write decoded text to {:?}: {}", text_path, e)
+            // Check newer_only setting is enabled and skip if destination is newer, unless forced
+            if settings.newer_only && !settings.force && text_path.exists() && archive_path.exists()
+            {
+                let archive_metadata = std::fs::metadata(archive_path).map_err(|e| {
+                    format!(
+                        "Failed to get metadata for archive {:?}: {}",
+                        archive_path, e
+                    )
                 })?;
-            }
-
-            if settings.newer_only {
-                // Update source archive file timestamp to match destination text file
                 let text_metadata = std::fs::metadata(text_path).map_err(|e| {
                     format!(
                         "Failed to get metadata for text file {:?}: {}",
                         text_path, e
                     )
                 })?;
-                let modified_time = text_metadata.modified().map_err(|e| {
+                let archive_modified = archive_metadata.modified().map_err(|e| {
                     format!(
-                        "Failed to get modified time for text file {:?}: {}",
-                        text_path, e
+                        "Failed to get modified time for archive {:?}: {}",
+                        archive_path, e
                     )
                 })?;
-                let archive_file = std::fs::File::open(archive_path).map_err(|e| {
-                    format!("Failed to open archive file {:?}: {}", archive_path, e)
-                })?;
-                archive_file.set_modified(modified_time).map_err(|e| {
+                let text_modified = text_metadata.modified().map_err(|e| {
                     format!(
-                        "Failed to update modified time for archive file {:?}: {}",
-                        archive_path, e
+                        "Failed to get modified time for text file {:?}: {}",
+                        text_path, e
                     )
                 })?;
+                if archive_modified <= text_modified {
+                    log::verbose!(
+                        "Skipping decoding of {:?} as destination {:?} is newer",
+                        archive_path,
+                        text_path
+                    );
+                    return Ok(archive::BatchStats {
+                        skipped: 1,
+                        ..Default::default()
+                    });
+                }
             }
 
-            Ok(())
+            log::verbose!("Decoding archive: {:?} -> {:?}", archive_path, text_path);
+
+            let (archive, truncated, size) =
+                decode_archive_file(charmap, archive_path, settings.msgenc_format, settings)?;
+            let stats = archive::BatchStats {
+                processed: 1,
+                messages: archive.messages.len(),
+                bytes: size,
+                ..Default::default()
+            };
+
+            // Everything has been decoded at this point, a dry run stops before touching any files
+            if settings.dry_run {
+                return Ok(stats);
+            }
+
+            if settings.recursive {
+                archive::create_parent_dir(text_path).map_err(|e| {
+                    format!("Failed to create directory for {:?}: {}", text_path, e)
+                })?;
+            }
+
+            write_decoded(&archive, truncated, text_path, settings)?;
+
+            if settings.hexdump {
+                write_hexdump(charmap, archive_path, &archive, Some(text_path), settings)?;
+            }
+
+            // Update source archive file timestamp to match destination text file. Sources may
+            // be read-only, so this only warns instead of failing the whole batch.
+            if settings.sync_mtime
+                && let Err(e) = archive::copy_modified_time(text_path, archive_path)
+            {
+                log::warning!(
+                    "Failed to update modified time for archive file {:?}: {}",
+                    archive_path,
+                    e
+                );
+            }
+
+            Ok(stats)
         })
+        .progress_with(progress.clone())
         .collect();
+    progress.finish();
 
     // Check for errors
+    let mut stats = archive::BatchStats::default();
     for result in results {
-        result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+        stats += result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+    }
+
+    if settings.stats {
+        stats.print(start.elapsed());
     }
 
     Ok(())
 }
 
+/// Decode archives holding the same messages in different languages into merged JSON files.
+///
+/// Archives are matched by file name: the last extension is the language code and the rest of
+/// the name identifies the archive, so `foo.en_US` and `foo.fr` are both written to `foo.json`.
+/// Every language of an archive must contain the same number of messages, since message ids are
+/// derived from the message index. The key stored in the JSON is taken from the `--lang`
+/// language if present, otherwise from the first language in alphabetical order.
+fn decode_archives_all_langs(
+    charmap: &charmap::Charmap,
+    archive_files: &[std::path::PathBuf],
+    archive_dir: Option<&std::path::Path>,
+    text_dir: &std::path::Path,
+    settings: &crate::Settings,
+) -> Result<archive::BatchStats, Box<dyn std::error::Error>> {
+    // Grouped by the JSON file, so archives with the same name in different subdirectories of a
+    // recursive run stay apart
+    let mut groups: BTreeMap<std::path::PathBuf, Vec<(String, std::path::PathBuf)>> =
+        BTreeMap::new();
+    for archive_path in archive_files {
+        let (Some(base), Some(lang)) = (
+            archive_path.file_stem().and_then(|s| s.to_str()),
+            archive_path.extension().and_then(|s| s.to_str()),
+        ) else {
+            return Err(format!(
+                "Archive {:?} has no language suffix (expected e.g. name.en_US)",
+                archive_path
+            )
+            .into());
+        };
+        let text_path =
+            archive::output_dir(text_dir, archive_path, archive_dir).join(format!("{}.json", base));
+        groups
+            .entry(text_path)
+            .or_default()
+            .push((lang.to_string(), archive_path.clone()));
+    }
+
+    let groups: Vec<_> = groups.into_iter().collect();
+
+    let progress = archive::progress_bar(groups.len(), settings.progress);
+
+    let results: Vec<Result<archive::BatchStats, String>> = groups
+        .par_iter()
+        .map(|(text_path, archives)| {
+            log::verbose!(
+                "Decoding {} language(s) -> {:?}",
+                archives.len(),
+                text_path
+            );
+
+            let mut stats = archive::BatchStats::default();
+            let mut decoded: Vec<(&str, TextArchive)> = Vec::with_capacity(archives.len());
+            for (lang, archive_path) in archives {
+                let (archive, _, size) =
+                    decode_archive_file(charmap, archive_path, false, settings)?;
+                stats += archive::BatchStats {
+                    processed: 1,
+                    messages: archive.messages.len(),
+                    bytes: size,
+                    ..Default::default()
+                };
+                decoded.push((lang.as_str(), archive));
+            }
+            decoded.sort_by(|a, b| a.0.cmp(b.0));
+
+            // Message ids are index based, so every language must have the same messages
+            let (first_lang, first) = &decoded[0];
+            for (lang, archive) in &decoded[1..] {
+                if archive.messages.len() != first.messages.len() {
+                    return Err(format!(
+                        "Message ids of {:?} don't align across languages: {} has {} messages but {} has {}",
+                        text_path,
+                        first_lang,
+                        first.messages.len(),
+                        lang,
+                        archive.messages.len()
+                    ));
+                }
+            }
+
+            let key = decoded
+                .iter()
+                .find(|(lang, _)| *lang == settings.lang)
+                .unwrap_or(&decoded[0])
+                .1
+                .key;
+
+            let languages: Vec<(&str, &TextArchive)> = decoded
+                .iter()
+                .map(|(lang, archive)| (*lang, archive))
+                .collect();
+
+            if settings.dry_run {
+                return Ok(stats);
+            }
+
+            if settings.recursive {
+                archive::create_parent_dir(text_path).map_err(|e| {
+                    format!("Failed to create directory for {:?}: {}", text_path, e)
+                })?;
+            }
+            write_decoded_json_langs(text_path, key, &languages)
+                .map_err(|e| format!("Failed to write decoded JSON to {:?}: {}", text_path, e))?;
+            Ok(stats)
+        })
+        .progress_with(progress.clone())
+        .collect();
+    progress.finish();
+
+    // Check for errors
+    let mut stats = archive::BatchStats::default();
+    for result in results {
+        stats += result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+    }
+
+    Ok(stats)
+}
+
+/// Line a section of a file written by `--combine` starts with, naming the archive it was decoded
+/// from relative to the archive directory. The file can be split up again at these lines, only a
+/// message that reads exactly like a header would be mistaken for one.
+fn combined_header(name: &str) -> String {
+    format!("=== {} ===", name)
+}
+
+/// Extension of the text files written to a text directory in the format the settings select.
+fn text_extension(settings: &crate::Settings) -> &'static str {
+    if settings.json {
+        "json"
+    } else if settings.csv {
+        "csv"
+    } else {
+        "txt"
+    }
+}
+
+/// Decode every archive of a container written by `encode --container` into its own text file
+/// in `text_dir`, named after its position in the container. The numbers are zero-padded so the
+/// files sort back into container order when encoded from the directory again.
+fn decode_container(
+    charmap: &charmap::Charmap,
+    container_path: &std::path::Path,
+    text_dir: &std::path::Path,
+    settings: &crate::Settings,
+) -> Result<archive::BatchStats, Box<dyn std::error::Error>> {
+    let container = archive::read_archive_file(container_path)
+        .map_err(|e| format!("Failed to read container {:?}: {}", container_path, e))?;
+    let archives = archive::unpack_container(&container, settings.endian)
+        .map_err(|e| format!("Failed to read container {:?}: {}", container_path, e))?;
+
+    let width = archives.len().saturating_sub(1).to_string().len().max(4);
+    let extension = text_extension(settings);
+
+    let progress = archive::progress_bar(archives.len(), settings.progress);
+
+    let results: Vec<Result<archive::BatchStats, String>> = archives
+        .par_iter()
+        .enumerate()
+        .map(|(index, data)| {
+            // Names the archive in messages like a path into the container
+            let archive_path = container_path.join(index.to_string());
+            let text_path = text_dir.join(format!("{:0width$}.{}", index, extension));
+            log::verbose!("Decoding archive: {:?} -> {:?}", archive_path, text_path);
+
+            let (archive, truncated, trailing) = decode_archive_data(
+                charmap,
+                data.to_vec(),
+                &archive_path,
+                settings.msgenc_format,
+                settings,
+            )
+            .map_err(|e| format!("Failed to decode archive {:?}: {}", archive_path, e))?;
+            check_trailing_data(trailing, &archive_path, settings)?;
+            let stats = archive::BatchStats {
+                processed: 1,
+                messages: archive.messages.len(),
+                bytes: data.len() as u64,
+                ..Default::default()
+            };
+
+            if !settings.dry_run {
+                write_decoded(&archive, truncated, &text_path, settings)?;
+            }
+            Ok(stats)
+        })
+        .progress_with(progress.clone())
+        .collect();
+    progress.finish();
+
+    let mut stats = archive::BatchStats::default();
+    for result in results {
+        stats += result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+    }
+    Ok(stats)
+}
+
+/// Decode archives into a single text file, each one under a [`combined_header`] line followed by
+/// its text exactly as it would be written to its own file.
+fn decode_archives_combined(
+    charmap: &charmap::Charmap,
+    archive_files: &[std::path::PathBuf],
+    archive_dir: Option<&std::path::Path>,
+    combined_path: &std::path::Path,
+    settings: &crate::Settings,
+) -> Result<archive::BatchStats, Box<dyn std::error::Error>> {
+    let line_ending = encode::TextLayout::from_settings(settings).line_ending();
+
+    let progress = archive::progress_bar(archive_files.len(), settings.progress);
+
+    let results: Vec<Result<(String, archive::BatchStats), String>> = archive_files
+        .par_iter()
+        .map(|archive_path| {
+            log::verbose!(
+                "Decoding archive: {:?} -> {:?}",
+                archive_path,
+                combined_path
+            );
+
+            let (archive, truncated, size) =
+                decode_archive_file(charmap, archive_path, settings.msgenc_format, settings)?;
+            let stats = archive::BatchStats {
+                processed: 1,
+                messages: archive.messages.len(),
+                bytes: size,
+                ..Default::default()
+            };
+
+            let name = archive_dir
+                .and_then(|dir| archive_path.strip_prefix(dir).ok())
+                .unwrap_or(archive_path);
+            let section = format!(
+                "{}{}{}",
+                combined_header(&name.to_string_lossy()),
+                line_ending,
+                decoded_text(&archive, truncated, settings)
+            );
+            Ok((section, stats))
+        })
+        .progress_with(progress.clone())
+        .collect();
+    progress.finish();
+
+    // Sections follow the sorted archive order, whichever file finished first
+    let mut content = String::new();
+    let mut stats = archive::BatchStats::default();
+    for result in results {
+        let (section, file_stats) =
+            result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+        content.push_str(&section);
+        stats += file_stats;
+    }
+
+    if !settings.dry_run {
+        archive::write_text_file(combined_path, &content, settings.encoding).map_err(|e| {
+            format!(
+                "Failed to write combined text to {:?}: {}",
+                combined_path, e
+            )
+        })?;
+    }
+
+    Ok(stats)
+}
+
+/// Decode a single archive file, either loaded into memory at once or, with `--stream`,
+/// read from disk one message at a time. Built with the `mmap` feature, other uncompressed
+/// archives are decoded from a memory mapping instead of a copy. Also returns the number of
+/// messages left out because of `--limit` and the size of the archive data.
+fn decode_archive_file(
+    charmap: &charmap::Charmap,
+    archive_path: &std::path::Path,
+    msgenc_format: bool,
+    settings: &crate::Settings,
+) -> Result<(TextArchive, usize, u64), String> {
+    // `--brute-key` rewrites the archive data, which a read-only mapping can't hold
+    #[cfg(all(feature = "mmap", unix))]
+    if !settings.stream && !settings.brute_key && !archive::is_gzip(archive_path) {
+        let mapped = crate::mmap::MappedFile::open(archive_path)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+        let archive = decode_archive_reader_limit(
+            charmap,
+            &mut Cursor::new(&*mapped),
+            msgenc_format,
+            settings.endian,
+            settings.limit,
+        );
+        return finish_archive_file(archive, mapped.len() as u64, archive_path, settings);
+    }
+
+    // Compressed archives can't be seeked in, so they are always decompressed into memory
+    let (archive, size) = if settings.stream && !archive::is_gzip(archive_path) {
+        let file = std::fs::File::open(archive_path)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+        let size = file
+            .metadata()
+            .map_err(|e| {
+                format!(
+                    "Failed to get metadata for archive {:?}: {}",
+                    archive_path, e
+                )
+            })?
+            .len();
+        let archive = decode_archive_reader_limit(
+            charmap,
+            &mut std::io::BufReader::new(file),
+            msgenc_format,
+            settings.endian,
+            settings.limit,
+        );
+        (archive, size)
+    } else {
+        let archive_file = archive::read_archive_file(archive_path)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+        let size = archive_file.len() as u64;
+        let archive =
+            decode_archive_data(charmap, archive_file, archive_path, msgenc_format, settings);
+        (archive, size)
+    };
+
+    finish_archive_file(archive, size, archive_path, settings)
+}
+
+/// Name the archive in a decoding error and check for data after the last message.
+fn finish_archive_file(
+    archive: Result<(TextArchive, usize, u64), ChatotError>,
+    size: u64,
+    archive_path: &std::path::Path,
+    settings: &crate::Settings,
+) -> Result<(TextArchive, usize, u64), String> {
+    let (archive, truncated, trailing) =
+        archive.map_err(|e| format!("Failed to decode archive {:?}: {}", archive_path, e))?;
+    check_trailing_data(trailing, archive_path, settings)?;
+
+    Ok((archive, truncated, size))
+}
+
+/// Decode an archive read into memory, recovering its key first with `--brute-key`.
+/// `archive_path` only names the archive in messages.
+fn decode_archive_data(
+    charmap: &charmap::Charmap,
+    mut archive_file: Vec<u8>,
+    archive_path: &std::path::Path,
+    msgenc_format: bool,
+    settings: &crate::Settings,
+) -> Result<(TextArchive, usize, u64), ChatotError> {
+    if settings.brute_key {
+        recover_archive_key(charmap, &mut archive_file, archive_path, settings.endian);
+    }
+    decode_archive_reader_limit(
+        charmap,
+        &mut Cursor::new(archive_file),
+        msgenc_format,
+        settings.endian,
+        settings.limit,
+    )
+}
+
+fn check_trailing_data(
+    trailing: u64,
+    archive_path: &std::path::Path,
+    settings: &crate::Settings,
+) -> Result<(), String> {
+    // Padding or a footer after the last message is skipped, but may be a section worth a look
+    if trailing > 0 {
+        if settings.strict {
+            return Err(format!(
+                "Archive {:?} has {} byte(s) after the last message that no message covers",
+                archive_path, trailing
+            ));
+        }
+        log::warning!(
+            "archive {:?} has {} byte(s) after the last message that no message covers",
+            archive_path,
+            trailing
+        );
+    }
+
+    Ok(())
+}
+
+/// Write a decoded archive as JSON, CSV or text, whichever the settings select.
+fn write_decoded(
+    archive: &TextArchive,
+    truncated: usize,
+    text_path: &std::path::Path,
+    settings: &crate::Settings,
+) -> Result<(), String> {
+    if settings.json {
+        write_decoded_json(archive, text_path, settings.lang.clone())
+            .map_err(|e| format!("Failed to write decoded JSON to {:?}: {}", text_path, e))
+    } else if settings.csv {
+        archive::write_text_file(text_path, &csv::archive_to_csv(archive), settings.encoding)
+            .map_err(|e| format!("Failed to write decoded CSV to {:?}: {}", text_path, e))
+    } else {
+        write_decoded_text(archive, truncated, text_path, settings)
+            .map_err(|e| format!("Failed to write decoded text to {:?}: {}", text_path, e))
+    }
+}
+
 fn write_decoded_text(
     archive: &TextArchive,
-    text_path: &std::path::PathBuf,
-    msgenc_format: bool,
+    truncated: usize,
+    text_path: &std::path::Path,
+    settings: &crate::Settings,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut content = archive.messages.join("\n");
+    let content = decoded_text(archive, truncated, settings);
+    archive::write_text_file(text_path, &content, settings.encoding)?;
 
-    if !msgenc_format {
+    Ok(())
+}
+
+/// Plain text file contents for a decoded archive, `truncated` being the number of messages
+/// left out at the end.
+fn decoded_text(archive: &TextArchive, truncated: usize, settings: &crate::Settings) -> String {
+    let mut content = String::new();
+    let layout = encode::TextLayout::from_settings(settings);
+    let line_ending = layout.line_ending();
+
+    if !settings.msgenc_format {
         // Prepend key as comment
-        content = format!("// Key: 0x{:04X}\n{}", archive.key, content);
+        content.push_str(&format!("// Key: 0x{:04X}{}", archive.key, line_ending));
     }
 
-    content.push('\n'); // Add trailing newline
-    std::fs::write(text_path, content)?;
+    // Every message is terminated by a line ending, so N messages are always N lines,
+    // including empty messages and archives without any messages
+    for (index, message) in archive.messages.iter().enumerate() {
+        // Index comments are skipped like any other comment when encoding
+        if settings.annotate {
+            content.push_str(&format!("// [{:03}]{}", index, line_ending));
+        }
+        if layout.markers {
+            content.push_str(&encode::message_marker(index));
+            content.push_str(line_ending);
+        }
+        if settings.expand_trainer_names {
+            let (names, message) = expand_trainer_names(message);
+            for name in names {
+                content.push_str(&format!(
+                    "{}{}{}",
+                    encode::TRAINER_NAME_PREFIX,
+                    name,
+                    line_ending
+                ));
+            }
+            content.push_str(&message);
+        } else {
+            content.push_str(message);
+        }
+        content.push_str(line_ending);
+    }
 
-    Ok(())
+    // Make a partial file obvious, encoding refuses a file ending with this comment
+    if truncated > 0 {
+        content.push_str(&format!(
+            "{}{} more messages){}",
+            encode::TRUNCATED_PREFIX,
+            truncated,
+            line_ending
+        ));
+    }
+
+    content
+}
+
+/// Move the names out of the `{TRAINER_NAME:...}` commands of a message, leaving placeholders
+/// that are filled in again from `// Trainer name:` lines when encoding.
+fn expand_trainer_names(message: &str) -> (Vec<&str>, String) {
+    const COMMAND_START: &str = "{TRAINER_NAME:";
+
+    let mut names = Vec::new();
+    let mut expanded = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find(COMMAND_START) {
+        let name_start = start + COMMAND_START.len();
+        // Names never contain '}', the encoder ends the command at the first one
+        let Some(name_len) = rest[name_start..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(encode::TRAINER_NAME_PLACEHOLDER);
+        names.push(&rest[name_start..name_start + name_len]);
+        rest = &rest[name_start + name_len + 1..];
+    }
+    expanded.push_str(rest);
+
+    (names, expanded)
 }
 
 fn write_decoded_json(
     archive: &TextArchive,
-    text_path: &std::path::PathBuf,
+    text_path: &std::path::Path,
     lang: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_decoded_json_langs(text_path, archive.key, &[(lang.as_str(), archive)])
+}
+
+/// Write one or more language versions of the same archive into a single JSON file,
+/// merging them into any languages already present in the file.
+fn write_decoded_json_langs(
+    text_path: &std::path::Path,
+    key: u16,
+    languages: &[(&str, &TextArchive)],
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Determine archive name from text_path file name
     let archive_name = text_path
@@ -212,144 +828,644 @@ fn write_decoded_json(
         .unwrap_or("archive");
 
     // If JSON already exists, load it to merge languages
-    let mut existing_messages: HashMap<String, JsonMessage> = HashMap::new();
-    if text_path.exists() {
-        if let Ok(existing_str) = std::fs::read_to_string(text_path) {
-            if let Ok(existing_json) = serde_json::from_str::<JsonOutput>(&existing_str) {
-                for msg in existing_json.messages {
-                    existing_messages.insert(msg.id.clone(), msg);
-                }
-            }
+    let mut existing_messages: BTreeMap<String, JsonMessage> = BTreeMap::new();
+    if text_path.exists()
+        && let Ok(existing_str) = std::fs::read_to_string(text_path)
+        && let Ok(existing_json) = serde_json::from_str::<JsonOutput>(&existing_str)
+    {
+        for msg in existing_json.messages {
+            existing_messages.insert(msg.id.clone(), msg);
         }
     }
 
-    let mut seen_ids: HashSet<String> = HashSet::new();
+    let output = build_json_output(archive_name, key, languages, existing_messages);
 
-    let mut json_messages: Vec<JsonMessage> = archive
-        .messages
-        .iter()
-        .enumerate()
-        .map(|(idx, msg)| {
-            let id = format!("msg_{}_{:05}", archive_name, idx);
-            seen_ids.insert(id.clone());
+    let json_string = serde_json::to_string_pretty(&output)?;
+    archive::write_file_atomic(text_path, json_string.as_bytes())?;
 
-            // Split message by literal \n, \r or \f sequences
-            // This gives us pretty printing while keeping the custom line breaks intact
-            let mut lines: Vec<String> = Vec::new();
-            let mut current = String::new();
-
-            for ch in msg.chars() {
-                current.push(ch);
-                if current.ends_with("\\n") || current.ends_with("\\r") || current.ends_with("\\f")
-                {
-                    lines.push(current.clone());
-                    current.clear();
-                }
-            }
+    Ok(())
+}
 
-            if !current.is_empty() {
-                lines.push(current);
-            }
+/// Decode an archive file into a JSON document with the same layout `encode` reads with `--json`.
+/// Message ids are derived from the archive file name and message index.
+#[allow(dead_code)]
+pub fn decode_to_json(
+    charmap: &charmap::Charmap,
+    archive_file: &std::path::Path,
+    lang: &str,
+    endian: Endian,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let archive_name = archive_file
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("archive");
 
-            let content = if lines.len() == 1 {
-                MessageContent::Single(msg.clone())
-            } else {
-                MessageContent::Multi(lines)
-            };
+    let archive_data = archive::read_archive_file(archive_file)?;
+    let archive = decode_archive(charmap, &archive_data, false, endian)?;
+
+    let output = build_json_output(
+        archive_name,
+        archive.key,
+        &[(lang, &archive)],
+        BTreeMap::new(),
+    );
+
+    Ok(serde_json::to_string_pretty(&output)?)
+}
+
+/// Build the JSON document for an archive. All languages must hold the same number of messages.
+fn build_json_output(
+    archive_name: &str,
+    key: u16,
+    languages: &[(&str, &TextArchive)],
+    mut existing_messages: BTreeMap<String, JsonMessage>,
+) -> JsonOutput {
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let message_count = languages
+        .first()
+        .map(|(_, archive)| archive.messages.len())
+        .unwrap_or(0);
+
+    let mut json_messages: Vec<JsonMessage> = (0..message_count)
+        .map(|idx| {
+            let id = format!("msg_{}_{:05}", archive_name, idx);
+            seen_ids.insert(id.clone());
 
             let mut merged = existing_messages.remove(&id).unwrap_or(JsonMessage {
                 id: id.clone(),
-                lang_message: HashMap::new(),
+                lang_message: BTreeMap::new(),
             });
 
-            merged.lang_message.insert(lang.clone(), content);
+            for (lang, archive) in languages {
+                let content = json_message_content(&archive.messages[idx]);
+                merged.lang_message.insert(lang.to_string(), content);
+            }
             merged
         })
         .collect();
 
-    // Preserve any existing messages not present in the current archive (GF can not be trusted)
+    // Preserve any existing messages not present in the current archive (GF can not be trusted),
+    // in the order of their ids
     for (id, msg) in existing_messages.into_iter() {
         if !seen_ids.contains(&id) {
             json_messages.push(msg);
         }
     }
 
-    let output = JsonOutput {
-        key: archive.key,
+    JsonOutput {
+        key,
         messages: json_messages,
-    };
+    }
+}
 
-    let json_string = serde_json::to_string_pretty(&output)?;
-    std::fs::write(text_path, json_string)?;
+fn json_message_content(msg: &str) -> MessageContent {
+    // Split message by literal \n, \r or \f sequences
+    // This gives us pretty printing while keeping the custom line breaks intact
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for ch in msg.chars() {
+        current.push(ch);
+        if current.ends_with("\\n") || current.ends_with("\\r") || current.ends_with("\\f") {
+            lines.push(current.clone());
+            current.clear();
+        }
+    }
 
-    Ok(())
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    if lines.len() <= 1 {
+        MessageContent::Single(msg.to_string())
+    } else {
+        MessageContent::Multi(lines)
+    }
 }
 
-pub fn decode_archive<R: std::io::Read + std::io::Seek>(
+/// Decode an archive held in memory into its key and messages.
+///
+/// ```
+/// use chatot::{Endian, decode_archive, get_default_charmap};
+///
+/// // One message "A" followed by the terminator, archive key 0
+/// let archive = [
+///     0x01, 0x00, 0x00, 0x00, // message count, key
+///     0x0C, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // offset 12, length 2
+///     0xF8, 0x1A, 0xEF, 0x9A, // encrypted message
+/// ];
+/// let decoded = decode_archive(get_default_charmap(), &archive, false, Endian::Little).unwrap();
+/// assert_eq!(decoded.key, 0);
+/// assert_eq!(decoded.messages, ["A"]);
+/// ```
+pub fn decode_archive(
+    charmap: &charmap::Charmap,
+    archive: &[u8],
+    msgenc_format: bool,
+    endian: Endian,
+) -> Result<TextArchive, ChatotError> {
+    decode_archive_reader(charmap, &mut Cursor::new(archive), msgenc_format, endian)
+}
+
+/// Decode an archive from any seekable reader. The message table is read first, then each
+/// message is seeked to and read on its own, so the archive never has to be fully in memory.
+pub fn decode_archive_reader<R: std::io::Read + std::io::Seek>(
     charmap: &charmap::Charmap,
     reader: &mut R,
     msgenc_format: bool,
-) -> Result<TextArchive, Box<dyn std::error::Error>> {
-    // Read u16 message count (2 bytes)
-    let message_count = reader.read_u16::<LittleEndian>()?;
-    let mut messages = Vec::with_capacity((message_count as usize) * 40); // Rough estimate
-    // Read u16 key (2 bytes)
-    let key = reader.read_u16::<LittleEndian>()?;
+    endian: Endian,
+) -> Result<TextArchive, ChatotError> {
+    decode_archive_reader_limit(charmap, reader, msgenc_format, endian, None)
+        .map(|(archive, _, _)| archive)
+}
 
-    // Read message table entries
-    let mut message_table = Vec::new();
-    for i in 0..message_count {
-        let mut offset = reader.read_u32::<LittleEndian>()?;
-        let mut length = reader.read_u32::<LittleEndian>()?;
+/// Like [`decode_archive_reader`], but only decode the first `limit` messages. The whole message
+/// table is still read and validated. Returns the archive, the number of messages left out and
+/// the number of bytes after the last message that no message covers.
+pub(crate) fn decode_archive_reader_limit<R: std::io::Read + std::io::Seek>(
+    charmap: &charmap::Charmap,
+    reader: &mut R,
+    msgenc_format: bool,
+    endian: Endian,
+    limit: Option<usize>,
+) -> Result<(TextArchive, usize, u64), ChatotError> {
+    let (key, message_table) = read_message_table_from(reader, endian, charmap.crypto.table_seed)?;
 
-        let mut local_key: u32 = 765;
-        local_key = local_key.wrapping_mul((i + 1) as u32);
-        local_key = local_key.wrapping_mul(key as u32);
-        local_key &= 0xFFFF;
+    // Seeking past the end succeeds for files and cursors, so compare against the length instead
+    let archive_len = reader.seek(std::io::SeekFrom::End(0))?;
 
-        local_key |= local_key << 16;
-        offset ^= local_key;
-        length ^= local_key;
+    validate_message_table(&message_table, archive_len)?;
+    let trailing = trailing_data_len(&message_table, archive_len);
 
-        message_table.push(MessageTableEntry { offset, length });
-    }
+    let decoded_count = limit.map_or(message_table.len(), |limit| limit.min(message_table.len()));
+    let mut messages = Vec::with_capacity(decoded_count);
 
     // Read and decode messages
-    for (i, entry) in message_table.iter().enumerate() {
-        // Ensure offset and length are within bounds (length is in u16 units)
-        // Check if seeking to the end of the message would fail
+    for (i, entry) in message_table.iter().enumerate().take(decoded_count) {
+        messages.push(read_message(
+            charmap,
+            reader,
+            entry,
+            i,
+            msgenc_format,
+            endian,
+        )?);
+    }
+
+    let truncated = message_table.len() - decoded_count;
+    Ok((TextArchive { key, messages }, truncated, trailing))
+}
+
+/// Number of bytes between the end of the message table and messages and the end of the archive.
+/// An archive ending on the next 4 byte boundary instead only has padding like `--align` writes.
+fn trailing_data_len(message_table: &[MessageTableEntry], archive_len: u64) -> u64 {
+    let header_size = 4; // 2 bytes for message count + 2 bytes for key
+    let data_start = header_size + (message_table.len() * archive::TABLE_ENTRY_SIZE) as u64;
+
+    let covered_end = message_table
+        .iter()
+        .map(|entry| entry.offset as u64 + entry.length as u64 * 2)
+        .fold(data_start, u64::max);
+
+    if archive_len <= covered_end.next_multiple_of(4) {
+        return 0;
+    }
+    archive_len - covered_end
+}
+
+/// Seek to the message of a table entry, the one at `index` counting from 0, and decode it.
+/// The entry has to be checked by [`validate_message_table`] first, which keeps a corrupt length
+/// from allocating more than the archive holds.
+fn read_message<R: std::io::Read + std::io::Seek>(
+    charmap: &charmap::Charmap,
+    reader: &mut R,
+    entry: &MessageTableEntry,
+    index: usize,
+    msgenc_format: bool,
+    endian: Endian,
+) -> Result<String, ChatotError> {
+    let encrypted_message = read_encrypted_message(reader, entry, endian)?;
+    let decrypted_message =
+        decrypt_message(&encrypted_message, (index + 1) as u16, &charmap.crypto);
+
+    Ok(decode_message_to_string(
+        charmap,
+        &decrypted_message,
+        msgenc_format,
+    ))
+}
+
+/// Seek to the message of a validated table entry and read its codes as they are stored.
+fn read_encrypted_message<R: std::io::Read + std::io::Seek>(
+    reader: &mut R,
+    entry: &MessageTableEntry,
+    endian: Endian,
+) -> Result<Vec<u16>, ChatotError> {
+    // Seek to the actual message start position
+    reader.seek(std::io::SeekFrom::Start(entry.offset as u64))?;
+    let mut encrypted_message = vec![0u16; entry.length as usize];
+    for c in encrypted_message.iter_mut() {
+        *c = endian.read_u16(reader)?;
+    }
+    Ok(encrypted_message)
+}
+
+/// Codes written on each line of a `--hexdump`.
+const HEXDUMP_CODES_PER_LINE: usize = 16;
+
+/// Dump the codes of every decoded message as stored and after decryption, followed by the text
+/// they decoded to, for finding out where unexpected codes come from. `key` is the key the
+/// messages were decoded with, which differs from the stored one after `--brute-key`.
+fn archive_hexdump(
+    charmap: &charmap::Charmap,
+    archive: &[u8],
+    key: u16,
+    messages: &[String],
+    endian: Endian,
+) -> Result<String, ChatotError> {
+    // The table is read with key 0, so the entries are still encrypted
+    let (_, encrypted_table) = read_message_table(archive, endian, 0)?;
+    let message_table = decrypt_message_table(&encrypted_table, key, charmap.crypto.table_seed);
+    validate_message_table(&message_table, archive.len() as u64)?;
+
+    let codes_lines = |label: &str, codes: &[u16]| {
+        let mut lines = String::new();
+        for (number, chunk) in codes.chunks(HEXDUMP_CODES_PER_LINE).enumerate() {
+            let label = if number == 0 { label } else { "" };
+            let codes: Vec<String> = chunk.iter().map(|code| format!("{:04X}", code)).collect();
+            lines.push_str(&format!("  {:<10} {}\n", label, codes.join(" ")));
+        }
+        lines
+    };
+
+    let mut reader = Cursor::new(archive);
+    let mut dump = String::new();
+    for (index, (entry, message)) in message_table.iter().zip(messages).enumerate() {
+        let encrypted_message = read_encrypted_message(&mut reader, entry, endian)?;
+        let decrypted_message =
+            decrypt_message(&encrypted_message, (index + 1) as u16, &charmap.crypto);
+
+        dump.push_str(&format!(
+            "[{:03}] offset 0x{:08X}, {} code(s)\n",
+            index, entry.offset, entry.length
+        ));
+        dump.push_str(&codes_lines("encrypted:", &encrypted_message));
+        dump.push_str(&codes_lines("decrypted:", &decrypted_message));
+        let text_line = format!("  {:<10} {}", "text:", message);
+        dump.push_str(text_line.trim_end());
+        dump.push('\n');
+    }
+    Ok(dump)
+}
+
+/// Write the `--hexdump` of a decoded archive next to its text file, or to stderr if the text
+/// is printed to stdout.
+fn write_hexdump(
+    charmap: &charmap::Charmap,
+    archive_path: &std::path::Path,
+    archive: &TextArchive,
+    text_path: Option<&std::path::Path>,
+    settings: &crate::Settings,
+) -> Result<(), String> {
+    let data = archive::read_archive_file(archive_path)
+        .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+    let dump = archive_hexdump(
+        charmap,
+        &data,
+        archive.key,
+        &archive.messages,
+        settings.endian,
+    )
+    .map_err(|e| format!("Failed to dump archive {:?}: {}", archive_path, e))?;
+
+    match text_path {
+        Some(text_path) => {
+            let hex_path = text_path.with_extension("hex");
+            archive::write_file_atomic(&hex_path, dump.as_bytes())
+                .map_err(|e| format!("Failed to write hex dump to {:?}: {}", hex_path, e))
+        }
+        None => {
+            eprint!("{dump}");
+            Ok(())
+        }
+    }
+}
+
+/// Read and validate the message table of an archive up front, then decode one message on each
+/// call to `next`, so the messages never have to be collected and decoding can stop early.
+#[allow(dead_code)]
+pub fn decode_messages_iter<'a>(
+    charmap: &'a charmap::Charmap,
+    archive: &'a [u8],
+    msgenc_format: bool,
+    endian: Endian,
+) -> Result<impl Iterator<Item = Result<String, ChatotError>> + 'a, ChatotError> {
+    let (_, message_table) = read_message_table(archive, endian, charmap.crypto.table_seed)?;
+    validate_message_table(&message_table, archive.len() as u64)?;
+
+    let mut reader = Cursor::new(archive);
+    Ok(message_table
+        .into_iter()
+        .enumerate()
+        .map(move |(index, entry)| {
+            read_message(charmap, &mut reader, &entry, index, msgenc_format, endian)
+        }))
+}
+
+/// Decrypt and decode the data of a single message without any archive header or table, as the
+/// message at `index` (counting from 0) of an archive. Trailing data after the codes is ignored.
+pub fn decode_raw_message(
+    charmap: &charmap::Charmap,
+    data: &[u8],
+    index: u16,
+    msgenc_format: bool,
+    endian: Endian,
+) -> Result<String, ChatotError> {
+    let mut reader = Cursor::new(data);
+    let mut encrypted_message = vec![0u16; data.len() / 2];
+    for c in encrypted_message.iter_mut() {
+        *c = endian.read_u16(&mut reader)?;
+    }
+    let decrypted_message =
+        decrypt_message(&encrypted_message, index.wrapping_add(1), &charmap.crypto);
+
+    Ok(decode_message_to_string(
+        charmap,
+        &decrypted_message,
+        msgenc_format,
+    ))
+}
+
+/// Share of message codes the charmap has to know for `--brute-key` to keep the stored key.
+const MIN_KNOWN_RATIO: f64 = 0.9;
+
+/// Find the key of an archive whose stored key is corrupted or unknown by trying all of them.
+/// Keys whose message table doesn't fit the archive are left out, the others are scored by the
+/// share of message codes the charmap knows. Returns the best key with that share, preferring
+/// the stored key on a tie, or `None` if no key gives a valid message table.
+pub fn recover_key(
+    charmap: &charmap::Charmap,
+    archive: &[u8],
+    endian: Endian,
+) -> Option<(u16, f64)> {
+    // The table is read with key 0, so the entries are still encrypted
+    let (stored_key, encrypted_table) = read_message_table(archive, endian, 0).ok()?;
+
+    let mut best: Option<(u16, f64)> = None;
+    for key in std::iter::once(stored_key).chain(0..=u16::MAX) {
+        let message_table = decrypt_message_table(&encrypted_table, key, charmap.crypto.table_seed);
+        if validate_message_table(&message_table, archive.len() as u64).is_err() {
+            continue;
+        }
+        let ratio = known_code_ratio(charmap, archive, &message_table, endian);
+        if best.is_none_or(|(_, best_ratio)| ratio > best_ratio) {
+            best = Some((key, ratio));
+        }
+    }
+    best
+}
+
+/// Share of the codes of all messages that decode to something the charmap knows. Command
+/// parameters and packed trainer names are counted as known, a message without terminator
+/// counts as one more unknown code. Expects a validated message table.
+fn known_code_ratio(
+    charmap: &charmap::Charmap,
+    archive: &[u8],
+    message_table: &[MessageTableEntry],
+    endian: Endian,
+) -> f64 {
+    let codes = &charmap.control_codes;
+    let (mut known, mut total) = (0usize, 0usize);
+
+    for (index, entry) in message_table.iter().enumerate() {
+        let start = entry.offset as usize;
+        let encrypted_message: Vec<u16> = archive[start..start + entry.length as usize * 2]
+            .chunks_exact(2)
+            .filter_map(|pair| endian.read_u16(&mut &pair[..]).ok())
+            .collect();
+        let decrypted_message =
+            decrypt_message(&encrypted_message, (index + 1) as u16, &charmap.crypto);
+
+        let mut terminated = false;
+        let mut i = 0;
+        while i < decrypted_message.len() {
+            let code = decrypted_message[i];
+            total += 1;
+            if code == codes.terminator || code == codes.trainer_name {
+                known += 1;
+                terminated = true;
+                break;
+            } else if code == codes.command_marker {
+                known += 1;
+                let param_count = decrypted_message.get(i + 2).map_or(0, |&count| count);
+                i += 3 + param_count as usize;
+                continue;
+            } else if charmap.decode_map.contains_key(&code) {
+                known += 1;
+            }
+            i += 1;
+        }
+        if !terminated {
+            total += 1;
+        }
+    }
+
+    if total == 0 {
+        return 1.0;
+    }
+    known as f64 / total as f64
+}
+
+/// With `--brute-key`, replace the stored key of an archive in memory by the one
+/// [`recover_key`] finds if the stored key gives an invalid message table or mostly unknown
+/// codes. The recovered key is reported, and written again when the text is encoded.
+fn recover_archive_key(
+    charmap: &charmap::Charmap,
+    archive: &mut [u8],
+    archive_path: &std::path::Path,
+    endian: Endian,
+) {
+    // Archives too short for a header fail to decode later on with a proper error
+    let Ok((stored_key, message_table)) =
+        read_message_table(archive, endian, charmap.crypto.table_seed)
+    else {
+        return;
+    };
+    if validate_message_table(&message_table, archive.len() as u64).is_ok()
+        && known_code_ratio(charmap, archive, &message_table, endian) >= MIN_KNOWN_RATIO
+    {
+        return;
+    }
+
+    match recover_key(charmap, archive, endian) {
+        Some((key, ratio)) if key != stored_key => {
+            // The key follows the u16 message count
+            let _ = endian.write_u16(&mut &mut archive[2..4], key);
+            log::note!(
+                "Recovered key 0x{:04X} for archive {:?} (stored key 0x{:04X}, {:.0}% of codes known)",
+                key,
+                archive_path,
+                stored_key,
+                ratio * 100.0
+            );
+        }
+        Some(_) => log::warning!(
+            "no better key found for archive {:?}, keeping 0x{:04X}",
+            archive_path,
+            stored_key
+        ),
+        None => log::warning!(
+            "no key gives a valid message table for archive {:?}",
+            archive_path
+        ),
+    }
+}
+
+/// Ensure every message lies between the end of the message table and the end of the archive,
+/// starts on a u16 boundary and doesn't overlap any other message.
+fn validate_message_table(
+    message_table: &[MessageTableEntry],
+    archive_len: u64,
+) -> Result<(), ChatotError> {
+    let invalid = |index: usize, reason| {
+        let entry = message_table[index];
+        Err(ChatotError::InvalidArchive {
+            index,
+            offset: entry.offset,
+            length: entry.length,
+            archive_len,
+            reason,
+        })
+    };
+
+    let header_size = 4; // 2 bytes for message count + 2 bytes for key
+    let data_start = header_size + (message_table.len() * archive::TABLE_ENTRY_SIZE) as u64;
+
+    for (index, entry) in message_table.iter().enumerate() {
+        // Length is in u16 units
         let end_position = entry.offset as u64 + (entry.length as u64 * 2);
-        if let Err(_) = reader.seek(std::io::SeekFrom::Start(end_position)) {
-            return Err(format!(
-                "Invalid message entry offset/length: offset={}, length={}",
-                entry.offset, entry.length
-            )
-            .into());
+        if (entry.offset as u64) < data_start {
+            return invalid(index, "message starts inside the header or message table");
+        }
+        if entry.offset % 2 != 0 {
+            return invalid(index, "message is not aligned to a u16 boundary");
         }
+        if end_position > archive_len {
+            return invalid(index, "message extends past the end of the archive");
+        }
+    }
+
+    // Check neighbouring messages in offset order, empty messages can't overlap anything. Entries
+    // with the same offset and length share their data like `encode --dedup` writes them.
+    let mut by_offset: Vec<usize> = (0..message_table.len())
+        .filter(|&index| message_table[index].length > 0)
+        .collect();
+    by_offset.sort_by_key(|&index| (message_table[index].offset, message_table[index].length));
+    by_offset.dedup_by_key(|index| message_table[*index]);
+    for pair in by_offset.windows(2) {
+        let previous = message_table[pair[0]];
+        let previous_end = previous.offset as u64 + previous.length as u64 * 2;
+        if (message_table[pair[1]].offset as u64) < previous_end {
+            return invalid(pair[1], "message overlaps another message");
+        }
+    }
 
-        // Seek back to the actual message start position
-        reader.seek(std::io::SeekFrom::Start(entry.offset as u64))?;
-        let mut encrypted_message = vec![0u16; entry.length as usize];
-        encrypted_message
-            .iter_mut()
-            .for_each(|c| *c = reader.read_u16::<LittleEndian>().unwrap());
-        let decrypted_message = decrypt_message(&encrypted_message, (i + 1) as u16);
+    Ok(())
+}
 
-        let message_string = decode_message_to_string(&charmap, &decrypted_message, msgenc_format);
-        messages.push(message_string);
+/// Table entry of a message along with its decrypted codes.
+pub type DecryptedMessage = (MessageTableEntry, Vec<u16>);
+
+/// Read the key, the validated message table and the decrypted codes of every message of an
+/// archive, stopping short of decoding them to text.
+pub fn read_decrypted_messages(
+    archive: &[u8],
+    endian: Endian,
+    crypto: &CryptoParams,
+) -> Result<(u16, Vec<DecryptedMessage>), ChatotError> {
+    let (key, message_table) = read_message_table(archive, endian, crypto.table_seed)?;
+    validate_message_table(&message_table, archive.len() as u64)?;
+
+    let mut reader = Cursor::new(archive);
+    let mut messages = Vec::with_capacity(message_table.len());
+    for (index, entry) in message_table.into_iter().enumerate() {
+        let encrypted_message = read_encrypted_message(&mut reader, &entry, endian)?;
+        messages.push((
+            entry,
+            decrypt_message(&encrypted_message, (index + 1) as u16, crypto),
+        ));
     }
+    Ok((key, messages))
+}
 
-    Ok(TextArchive { key, messages })
+/// Read the key and the decrypted message table of an archive without decoding any messages.
+pub fn read_message_table(
+    archive: &[u8],
+    endian: Endian,
+    table_seed: u32,
+) -> Result<(u16, Vec<MessageTableEntry>), ChatotError> {
+    read_message_table_from(&mut Cursor::new(archive), endian, table_seed)
 }
 
-fn decrypt_message(encrypted_message: &Vec<u16>, index: u16) -> Vec<u16> {
+fn read_message_table_from<R: std::io::Read + std::io::Seek>(
+    reader: &mut R,
+    endian: Endian,
+    table_seed: u32,
+) -> Result<(u16, Vec<MessageTableEntry>), ChatotError> {
+    // Read u16 message count (2 bytes)
+    let message_count = endian.read_u16(reader)?;
+    // Read u16 key (2 bytes)
+    let key = endian.read_u16(reader)?;
+
+    // A garbage count shouldn't allocate a table the archive can't even hold
+    let table_start = reader.stream_position()?;
+    let archive_len = reader.seek(std::io::SeekFrom::End(0))?;
+    reader.seek(std::io::SeekFrom::Start(table_start))?;
+    if archive_len < table_start + (message_count as usize * archive::TABLE_ENTRY_SIZE) as u64 {
+        return Err(ChatotError::TruncatedTable {
+            count: message_count,
+            archive_len,
+        });
+    }
+
+    // Read message table entries
+    let mut encrypted_table = Vec::with_capacity(message_count as usize);
+    for _ in 0..message_count {
+        let offset = endian.read_u32(reader)?;
+        let length = endian.read_u32(reader)?;
+        encrypted_table.push(MessageTableEntry { offset, length });
+    }
+
+    let message_table = decrypt_message_table(&encrypted_table, key, table_seed);
+    Ok((key, message_table))
+}
+
+fn decrypt_message_table(
+    encrypted_table: &[MessageTableEntry],
+    key: u16,
+    table_seed: u32,
+) -> Vec<MessageTableEntry> {
+    encrypted_table
+        .iter()
+        .zip(1..)
+        .map(|(entry, index)| {
+            let local_key = crypto::table_key(key, index, table_seed);
+            MessageTableEntry {
+                offset: entry.offset ^ local_key,
+                length: entry.length ^ local_key,
+            }
+        })
+        .collect()
+}
+
+fn decrypt_message(encrypted_message: &[u16], index: u16, crypto: &CryptoParams) -> Vec<u16> {
     let mut decrypted_message = Vec::with_capacity(encrypted_message.len());
-    let mut current_key: u16 = (index as u32).wrapping_mul(596947) as u16;
+    let mut current_key: u16 = (index as u32).wrapping_mul(crypto.msg_mul) as u16;
 
     for &enc_char in encrypted_message {
         let dec_char = enc_char ^ current_key;
         decrypted_message.push(dec_char);
-        current_key = current_key.wrapping_add(18749);
+        current_key = current_key.wrapping_add(crypto.msg_add);
         current_key &= 0xFFFF;
     }
 
@@ -358,9 +1474,10 @@ fn decrypt_message(encrypted_message: &Vec<u16>, index: u16) -> Vec<u16> {
 
 pub fn decode_message_to_string(
     charmap: &charmap::Charmap,
-    decrypted_message: &Vec<u16>,
+    decrypted_message: &[u16],
     msgenc_format: bool,
 ) -> String {
+    let codes = &charmap.control_codes;
     let mut i = 0;
     let mut result = String::new();
 
@@ -368,30 +1485,29 @@ pub fn decode_message_to_string(
         let code = decrypted_message[i];
 
         // Termination character
-        if code == 0xFFFF {
+        if code == codes.terminator {
             break;
         // Special Command Character
-        } else if code == 0xFFFE {
+        } else if code == codes.command_marker {
             let (command, to_skip) =
                 decode_command(charmap, &decrypted_message[i..], msgenc_format);
             result.push_str(&command);
             i += to_skip;
         // Trainer Name
-        } else if code == 0xF100 {
+        } else if code == codes.trainer_name {
             let (trainer_name, to_skip) =
                 decode_trainer_name(charmap, &decrypted_message[i..], msgenc_format);
             result.push_str(&trainer_name);
             i += to_skip;
         // Regular character
-        } else if charmap.decode_map.contains_key(&code) {
-            let character = charmap.decode_map.get(&code).unwrap();
+        } else if let Some(character) = charmap.decode_map.get(&code) {
             result.push_str(character);
             i += 1;
         }
         // Unknown character code
         else {
-            eprintln!(
-                "Warning: unknown character code 0x{:04X} encountered during decoding",
+            log::warning!(
+                "unknown character code 0x{:04X} encountered during decoding",
                 code
             );
             result.push_str(&format!("\\x{:04X}", code));
@@ -400,8 +1516,8 @@ pub fn decode_message_to_string(
     }
 
     if (i + 1) < decrypted_message.len() {
-        eprintln!(
-            "Warning: extra data found after termination character in message. Ignoring remaining {} character codes.",
+        log::warning!(
+            "extra data found after termination character in message. Ignoring remaining {} character codes.",
             decrypted_message.len() - (i + 1)
         );
     }
@@ -415,12 +1531,16 @@ fn decode_command(
     msgenc_format: bool,
 ) -> (String, usize) {
     let mut result = String::new();
-    let mut to_skip = 1; // Skip the 0xFFFE code
+    let mut to_skip = 1; // Skip the command marker
+    let marker = charmap.control_codes.command_marker;
 
     // Stray command code
-    if message_slice.is_empty() {
-        eprintln!("Warning: stray command code 0xFFFE encountered with no following data");
-        result.push_str("\\xFFFE");
+    if message_slice.len() < 2 {
+        log::warning!(
+            "stray command code 0x{:04X} encountered with no following data",
+            marker
+        );
+        result.push_str(&format!("\\x{:04X}", marker));
         return (result, to_skip);
     }
 
@@ -429,41 +1549,46 @@ fn decode_command(
     to_skip += 1;
 
     // No param count (invalid)
-    if message_slice.len() < 2 {
-        eprintln!(
-            "Warning: command code 0x{:04X} encountered with no parameter count",
+    if message_slice.len() < 3 {
+        log::warning!(
+            "command code 0x{:04X} encountered with no parameter count",
             command_code
         );
-        result.push_str(&format!("\\xFFFE\\x{:04X}", command_code));
+        result.push_str(&format!("\\x{:04X}\\x{:04X}", marker, command_code));
         return (result, to_skip);
     }
 
     // Get number of parameters
     let param_count = message_slice[2];
-    to_skip += 1 + param_count as usize;
+    to_skip += 1;
 
-    // Not enough data for parameters
+    // Not enough data for parameters, leave whatever follows to be decoded as regular codes
     if message_slice.len() < (3 + param_count as usize) {
-        eprintln!(
-            "Warning: command code 0x{:04X} encountered with insufficient parameters (expected {}, found {})",
+        log::warning!(
+            "command code 0x{:04X} encountered with insufficient parameters (expected {}, found {})",
             command_code,
             param_count,
             message_slice.len() - 3
         );
         result.push_str(&format!(
-            "\\xFFFE\\x{:04X}\\x{:04X}",
-            command_code, param_count
+            "\\x{:04X}\\x{:04X}\\x{:04X}",
+            marker, command_code, param_count
         ));
         return (result, to_skip);
     }
 
+    to_skip += param_count as usize;
+
     // Decode parameters
     let mut params = message_slice[3..(3 + param_count as usize)].to_vec();
 
     let mut special_byte: u16 = 0;
 
     if !charmap.command_map.contains_key(&command_code)
-        && charmap.command_map.contains_key(&(command_code & 0xFF00))
+        && charmap
+            .command_map
+            .get(&(command_code & 0xFF00))
+            .is_some_and(|name| charmap.has_special_byte(name))
     {
         special_byte = command_code & 0x00FF;
         command_code &= 0xFF00;
@@ -472,8 +1597,8 @@ fn decode_command(
     let command_str = if let Some(cmd) = charmap.command_map.get(&command_code) {
         cmd.clone()
     } else {
-        eprintln!(
-            "Warning: unknown command code 0x{:04X} encountered during decoding",
+        log::warning!(
+            "unknown command code 0x{:04X} encountered during decoding",
             command_code
         );
         format!("0x{:04X}", command_code)
@@ -493,8 +1618,8 @@ fn decode_command(
     }
     // Msgenc format
     else {
-        // msgenc format omits the special byte if it is zero
-        if special_byte != 0 {
+        // msgenc format only writes the special byte for commands that have one
+        if charmap.has_special_byte(&command_str) {
             params.insert(0, special_byte);
         }
 
@@ -514,17 +1639,23 @@ fn decode_command(
     }
 }
 
+/// Longest trainer name that is decoded, far more than the games allow, so corrupt data without
+/// a termination code doesn't fill a whole message with name characters.
+const MAX_TRAINER_NAME_LEN: usize = 64;
+
 fn decode_trainer_name(
     charmap: &charmap::Charmap,
     message_slice: &[u16],
     msgenc_format: bool,
 ) -> (String, usize) {
     let mut result = String::new();
-    let mut to_skip = 1; // Skip the 0xF100 code
+    let mut to_skip = 1; // Skip the trainer name code
 
-    let mut bit = 0;
+    // Characters are 9-bit codes packed into the low 15 bits of the following u16s, starting with
+    // the least significant bit. Bits loaded but not yet decoded are kept in `pending`.
+    let mut pending: u32 = 0;
+    let mut pending_bits = 0;
     let mut index = 1;
-    let mut codes_consumed = 1;
 
     if !msgenc_format {
         result.push_str("{TRAINER_NAME:");
@@ -535,39 +1666,484 @@ fn decode_trainer_name(
         result.push_str("{TRNAME}");
     }
 
-    while index < message_slice.len() {
-        let mut code = (message_slice[index] >> bit) & 0x1FF;
-        bit += 9;
-
-        if bit >= 15 {
-            bit -= 15;
+    let codes = &charmap.control_codes;
+    let mut characters = 0;
+    let mut terminated = false;
+
+    loop {
+        // Load u16s until a whole code is available, the last one may be shared with the next code
+        while pending_bits < 9 && index < message_slice.len() {
+            let value = message_slice[index];
+            // Names at the end of a message are completed by the message terminator, so it and
+            // a command marker are never part of a name and are left for the caller
+            if value == codes.terminator || value == codes.command_marker {
+                terminated = value == codes.terminator;
+                break;
+            }
+            pending |= ((value & 0x7FFF) as u32) << pending_bits;
+            pending_bits += 15;
             index += 1;
-            codes_consumed += 1;
-
-            if bit != 0 && index < message_slice.len() {
-                code |= message_slice[index] << (9 - bit) & 0x1FF;
+        }
+        // Out of data without a termination code
+        if pending_bits < 9 {
+            if !terminated {
+                log::warning!(
+                    "trainer name without termination code, decoded {} character(s)",
+                    characters
+                );
             }
+            break;
         }
 
+        let code = (pending & 0x1FF) as u16;
+        pending >>= 9;
+        pending_bits -= 9;
+
         // Termination character
         if code == 0x1FF {
             break;
         }
 
+        // The rest of an overlong name is still skipped, it can't be anything else
+        characters += 1;
+        if characters > MAX_TRAINER_NAME_LEN {
+            if characters == MAX_TRAINER_NAME_LEN + 1 {
+                log::warning!(
+                    "trainer name longer than {} characters, the rest is left out",
+                    MAX_TRAINER_NAME_LEN
+                );
+            }
+            continue;
+        }
+
         if charmap.decode_map.contains_key(&code) {
             let character = charmap.decode_map.get(&code).unwrap();
             result.push_str(character);
         } else {
-            result.push_str(&format!("0x{:04X}", code));
+            result.push_str(&format!("\\x{:04X}", code));
         }
     }
 
     // Close trainer name tag for non-msgenc format
     if !msgenc_format {
-        result.push_str("}");
+        result.push('}');
     }
 
-    to_skip += codes_consumed;
+    // Skip every u16 a code was read from
+    to_skip += index - 1;
 
     (result, to_skip)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_command_keeps_truncated_commands_as_escapes() {
+        let charmap = charmap::get_default_charmap();
+        assert_eq!(
+            decode_command(charmap, &[0xFFFE], false),
+            ("\\xFFFE".to_string(), 1)
+        );
+        assert_eq!(
+            decode_command(charmap, &[0xFFFE, 0x0102], false),
+            ("\\xFFFE\\x0102".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn command_code_outside_a_command_decodes_as_an_escape() {
+        let charmap = charmap::decode_charmap(
+            r#"{
+                "char_map": { "0145": { "char": "a" } },
+                "command_map": { "0200": "YESNO" }
+            }"#,
+        )
+        .unwrap();
+        let codes = &charmap.control_codes;
+        assert!(!charmap.decode_map.contains_key(&0x0200));
+
+        // After the command marker the code names a command, on its own it is no character,
+        // just like a code found in neither map
+        let message = [
+            0x0145,
+            codes.command_marker,
+            0x0200,
+            0x0001,
+            0x0001,
+            0x0200,
+            0x0999,
+            codes.terminator,
+        ];
+        assert_eq!(
+            decode_message_to_string(&charmap, &message, false),
+            "a{YESNO, 0, 1}\\x0200\\x0999"
+        );
+    }
+
+    fn entry(offset: u32, length: u32) -> MessageTableEntry {
+        MessageTableEntry { offset, length }
+    }
+
+    /// Reason of the error validating a table of two messages, whose data starts at byte 20.
+    fn invalid_reason(table: &[MessageTableEntry], archive_len: u64) -> &'static str {
+        match validate_message_table(table, archive_len) {
+            Err(ChatotError::InvalidArchive { reason, .. }) => reason,
+            other => panic!("expected an invalid archive, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn validate_message_table_accepts_messages_after_the_table() {
+        let table = [entry(20, 2), entry(24, 3)];
+        assert!(validate_message_table(&table, 30).is_ok());
+    }
+
+    #[test]
+    fn validate_message_table_rejects_each_invalid_entry() {
+        assert_eq!(
+            invalid_reason(&[entry(20, 1), entry(16, 1)], 30),
+            "message starts inside the header or message table"
+        );
+        assert_eq!(
+            invalid_reason(&[entry(20, 1), entry(23, 1)], 30),
+            "message is not aligned to a u16 boundary"
+        );
+        assert_eq!(
+            invalid_reason(&[entry(20, 1), entry(28, 2)], 30),
+            "message extends past the end of the archive"
+        );
+        assert_eq!(
+            invalid_reason(&[entry(20, 3), entry(24, 1)], 30),
+            "message overlaps another message"
+        );
+    }
+
+    #[test]
+    fn validate_message_table_allows_shared_but_not_partly_overlapping_data() {
+        assert!(validate_message_table(&[entry(20, 2), entry(20, 2)], 30).is_ok());
+        assert_eq!(
+            invalid_reason(&[entry(20, 2), entry(20, 3)], 30),
+            "message overlaps another message"
+        );
+        assert_eq!(
+            invalid_reason(&[entry(20, 2), entry(22, 2)], 30),
+            "message overlaps another message"
+        );
+    }
+
+    #[test]
+    fn validate_message_table_ignores_empty_messages_when_checking_overlaps() {
+        assert!(validate_message_table(&[entry(20, 2), entry(22, 0)], 30).is_ok());
+    }
+
+    #[test]
+    fn validate_message_table_matches_a_simple_model_for_every_small_entry() {
+        // Sweep the second of two entries over every small offset and length, the first one
+        // covers bytes 24..28 of a 34 byte archive
+        for offset in 0..40 {
+            for length in 0..8 {
+                let end = offset + length * 2;
+                let overlaps = length > 0 && offset < 28 && end > 24 && (offset, length) != (24, 2);
+                let valid = offset >= 20 && offset % 2 == 0 && end <= 34 && !overlaps;
+                let table = [entry(24, 2), entry(offset, length)];
+                assert_eq!(
+                    validate_message_table(&table, 34).is_ok(),
+                    valid,
+                    "offset {offset}, length {length}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_archive_error_names_the_failing_entry() {
+        // Data of three messages starts at byte 28
+        let table = [entry(28, 1), entry(30, 1), entry(32, 4)];
+        match validate_message_table(&table, 36) {
+            Err(ChatotError::InvalidArchive {
+                index,
+                offset,
+                length,
+                archive_len,
+                ..
+            }) => assert_eq!((index, offset, length, archive_len), (2, 32, 4, 36)),
+            other => panic!("expected an invalid archive, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn empty_messages_round_trip_through_plain_text() {
+        let archive = TextArchive {
+            key: 0x1234,
+            messages: vec![String::new(), "hi".to_string(), String::new()],
+        };
+        let settings = crate::Settings::default();
+        let text = decoded_text(&archive, 0, &settings);
+        assert_eq!(text, "// Key: 0x1234\n\nhi\n\n");
+
+        let parsed =
+            encode::parse_text_messages(&text, encode::TextLayout::from_settings(&settings))
+                .unwrap();
+        assert_eq!(parsed.key, archive.key);
+        assert_eq!(parsed.messages, archive.messages);
+    }
+
+    #[test]
+    fn limited_text_is_refused_by_encoding() {
+        let archive = TextArchive {
+            key: 0x1234,
+            messages: vec!["a".to_string(), "b".to_string()],
+        };
+        let settings = crate::Settings::default();
+        let text = decoded_text(&archive, 3, &settings);
+        assert_eq!(
+            text,
+            "// Key: 0x1234\na\nb\n// ... truncated (3 more messages)\n"
+        );
+
+        let layout = encode::TextLayout::from_settings(&settings);
+        match encode::parse_text_messages(&text, layout) {
+            Err(ChatotError::InvalidText { line, reason }) => {
+                assert_eq!(line, 4);
+                assert!(reason.contains("--limit"), "{reason}");
+            }
+            Err(other) => panic!("expected invalid text, got {other}"),
+            Ok(_) => panic!("text decoded with --limit was parsed"),
+        }
+
+        let complete = text.replace("// ... truncated (3 more messages)\n", "");
+        let parsed = encode::parse_text_messages(&complete, layout).unwrap();
+        assert_eq!(parsed.messages, archive.messages);
+    }
+
+    /// Trainer name block of 9-bit codes without the 0x1FF termination code, the unused bits of
+    /// the last u16 left as zeros.
+    fn unterminated_trainer_name(codes: &[u16]) -> Vec<u16> {
+        let mut block = vec![0xF100];
+        let (mut pending, mut pending_bits) = (0u32, 0);
+        for &code in codes {
+            pending |= (code as u32) << pending_bits;
+            pending_bits += 9;
+            while pending_bits >= 15 {
+                block.push((pending & 0x7FFF) as u16);
+                pending >>= 15;
+                pending_bits -= 15;
+            }
+        }
+        if pending_bits > 0 {
+            block.push(pending as u16);
+        }
+        block
+    }
+
+    #[test]
+    fn trainer_name_without_termination_code_stops_at_the_message_end() {
+        let charmap = charmap::get_default_charmap();
+        let codes: Vec<u16> = "Abc"
+            .chars()
+            .map(|ch| charmap.encode_map[&ch.to_string()])
+            .collect();
+        let block = unterminated_trainer_name(&codes);
+        assert_eq!(block.len(), 3);
+
+        for next in [
+            charmap.control_codes.terminator,
+            charmap.control_codes.command_marker,
+        ] {
+            let mut message = block.clone();
+            message.extend([next, 0x0001]);
+            assert_eq!(
+                decode_trainer_name(charmap, &message, false),
+                ("{TRAINER_NAME:Abc}".to_string(), block.len())
+            );
+        }
+        // Running out of codes gives the partial name as well
+        assert_eq!(
+            decode_trainer_name(charmap, &block, false),
+            ("{TRAINER_NAME:Abc}".to_string(), block.len())
+        );
+
+        // Overlong names are cut at the limit, but every code of the block is still skipped
+        let long: Vec<u16> = codes.iter().copied().cycle().take(70).collect();
+        let block = unterminated_trainer_name(&long);
+        let (name, skipped) = decode_trainer_name(charmap, &block, false);
+        assert_eq!(name.len(), "{TRAINER_NAME:}".len() + MAX_TRAINER_NAME_LEN);
+        assert_eq!(skipped, block.len());
+    }
+
+    #[test]
+    fn empty_archive_keeps_its_key() {
+        let charmap = charmap::get_default_charmap();
+        let archive = [0, 0, 0x34, 0x12];
+        let decoded = decode_archive(charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.key, 0x1234);
+        assert!(decoded.messages.is_empty());
+
+        let settings = crate::Settings::default();
+        let text = decoded_text(&decoded, 0, &settings);
+        assert_eq!(text, "// Key: 0x1234\n");
+        let parsed =
+            encode::parse_text_messages(&text, encode::TextLayout::from_settings(&settings))
+                .unwrap();
+        let encoded = encode::encode_archive(
+            charmap,
+            parsed.key,
+            &parsed.messages,
+            encode::EncodeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(encoded, archive);
+    }
+
+    #[test]
+    fn message_count_larger_than_the_archive_is_rejected() {
+        let charmap = charmap::get_default_charmap();
+        // 65535 table entries would need half a megabyte, the archive has 12 bytes
+        let archive = [0xFF, 0xFF, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0];
+        match decode_archive(charmap, &archive, false, Endian::Little) {
+            Err(ChatotError::TruncatedTable { count, archive_len }) => {
+                assert_eq!((count, archive_len), (0xFFFF, 12));
+            }
+            Err(other) => panic!("expected a truncated table, got {other}"),
+            Ok(_) => panic!("archive with a garbage count was decoded"),
+        }
+    }
+
+    #[test]
+    fn oversized_message_length_is_rejected_before_reading() {
+        let charmap = charmap::get_default_charmap();
+        // Key 0 leaves the table unencrypted, the one message claims 0xFFFFFFFF codes
+        let archive = [1, 0, 0, 0, 12, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        for stream in [false, true] {
+            let result = if stream {
+                decode_archive_reader(charmap, &mut Cursor::new(&archive), false, Endian::Little)
+            } else {
+                decode_archive(charmap, &archive, false, Endian::Little)
+            };
+            match result {
+                Err(ChatotError::InvalidArchive {
+                    index,
+                    offset,
+                    length,
+                    archive_len,
+                    reason,
+                }) => {
+                    assert_eq!((index, offset, length), (0, 12, u32::MAX));
+                    assert_eq!(archive_len, 14);
+                    assert_eq!(reason, "message extends past the end of the archive");
+                }
+                Err(other) => panic!("expected an invalid archive, got {other}"),
+                Ok(_) => panic!("archive with an oversized message was decoded"),
+            }
+        }
+    }
+
+    #[test]
+    fn explicit_files_must_pair_up() {
+        let dir = archive::test_dir("decode-mismatch");
+        for name in ["a.bin", "b.bin"] {
+            std::fs::write(dir.join(name), [0, 0, 0, 0]).unwrap();
+        }
+        let source = crate::BinarySource {
+            archive: Some(vec![dir.join("a.bin"), dir.join("b.bin")]),
+            archive_dir: None,
+            container: None,
+        };
+        let destination = crate::TextDestination {
+            txt: Some(vec![dir.join("a.txt")]),
+            text_dir: None,
+            stdout: false,
+            combine: None,
+        };
+        let settings = crate::Settings::default();
+        let error = decode_archives(
+            charmap::get_default_charmap(),
+            &source,
+            &destination,
+            &settings,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "2 inputs but 1 outputs specified");
+        assert!(!dir.join("a.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "mmap", unix))]
+    #[test]
+    fn mapped_archives_decode_like_read_ones() {
+        let charmap = charmap::get_default_charmap();
+        let dir = archive::test_dir("decode-mmap");
+        let messages = ["Hello".to_string(), String::new(), "World".to_string()];
+        let data =
+            encode::encode_archive(charmap, 0x1234, &messages, encode::EncodeOptions::default())
+                .unwrap();
+        std::fs::write(dir.join("0.bin"), &data).unwrap();
+        std::fs::write(dir.join("empty.bin"), []).unwrap();
+
+        assert_eq!(
+            &*crate::mmap::MappedFile::open(&dir.join("0.bin")).unwrap(),
+            data
+        );
+        assert!(
+            crate::mmap::MappedFile::open(&dir.join("empty.bin"))
+                .unwrap()
+                .is_empty()
+        );
+
+        let settings = crate::Settings::default();
+        let (decoded, truncated, size) =
+            decode_archive_file(charmap, &dir.join("0.bin"), false, &settings).unwrap();
+        assert_eq!(decoded.key, 0x1234);
+        assert_eq!(decoded.messages, messages);
+        assert_eq!((truncated, size), (0, data.len() as u64));
+
+        match decode_archive_file(charmap, &dir.join("empty.bin"), false, &settings) {
+            Err(error) => assert!(error.starts_with("Failed to decode archive"), "{error}"),
+            Ok(_) => panic!("empty archive was decoded"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Timing of decoding a directory of small archives from copies and from mappings, run with
+    /// `cargo test --release --features mmap -- --ignored --nocapture bench_`.
+    #[cfg(all(feature = "mmap", unix))]
+    #[test]
+    #[ignore]
+    fn bench_mmap_against_read_for_small_archives() {
+        const FILES: usize = 5000;
+        let charmap = charmap::get_default_charmap();
+        let dir = archive::test_dir("decode-mmap-bench");
+        let messages: Vec<String> = (0..10).map(|i| format!("Message number {i}")).collect();
+        let data =
+            encode::encode_archive(charmap, 0x1234, &messages, encode::EncodeOptions::default())
+                .unwrap();
+        let paths: Vec<_> = (0..FILES).map(|i| dir.join(format!("{i}.bin"))).collect();
+        for path in &paths {
+            std::fs::write(path, &data).unwrap();
+        }
+
+        let start = std::time::Instant::now();
+        for path in &paths {
+            let archive = std::fs::read(path).unwrap();
+            decode_archive(charmap, &archive, false, Endian::Little).unwrap();
+        }
+        let read_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for path in &paths {
+            let archive = crate::mmap::MappedFile::open(path).unwrap();
+            decode_archive(charmap, &archive, false, Endian::Little).unwrap();
+        }
+        let mmap_time = start.elapsed();
+
+        eprintln!(
+            "{FILES} archives of {} bytes: read {:.2?}, mmap {:.2?}",
+            data.len(),
+            read_time,
+            mmap_time
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
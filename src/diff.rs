@@ -0,0 +1,61 @@
+use std::path::Path;
+
+use crate::{
+    archive::{self, Endian},
+    charmap, decode,
+};
+
+/// Decode two archives and print the messages that were added, removed or changed, matched by
+/// message index. Fails if the archives differ in key or in any message.
+pub fn print_archive_diff(
+    charmap: &charmap::Charmap,
+    old_path: &Path,
+    new_path: &Path,
+    endian: Endian,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let decode_file = |archive_path: &Path| -> Result<decode::TextArchive, String> {
+        let archive_file = archive::read_archive_file(archive_path)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+        decode::decode_archive(charmap, &archive_file, false, endian)
+            .map_err(|e| format!("Failed to decode archive {:?}: {}", archive_path, e))
+    };
+    let old = decode_file(old_path)?;
+    let new = decode_file(new_path)?;
+
+    println!("--- {}", old_path.display());
+    println!("+++ {}", new_path.display());
+
+    let mut differences = 0;
+    if old.key != new.key {
+        differences += 1;
+        println!("Key: 0x{:04X} -> 0x{:04X}", old.key, new.key);
+    }
+
+    for index in 0..old.messages.len().max(new.messages.len()) {
+        match (old.messages.get(index), new.messages.get(index)) {
+            (Some(old_message), Some(new_message)) if old_message == new_message => continue,
+            (Some(old_message), Some(new_message)) => {
+                println!("@@ [{:03}] changed", index);
+                println!("-{}", old_message);
+                println!("+{}", new_message);
+            }
+            (Some(old_message), None) => {
+                println!("@@ [{:03}] removed", index);
+                println!("-{}", old_message);
+            }
+            (None, Some(new_message)) => {
+                println!("@@ [{:03}] added", index);
+                println!("+{}", new_message);
+            }
+            (None, None) => unreachable!(),
+        }
+        differences += 1;
+    }
+
+    if differences > 0 {
+        return Err(format!("Found {} difference(s) between the archives", differences).into());
+    }
+
+    println!("Archives are identical");
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+use crate::charmap;
+
+/// Charmap in the same layout that is read from charmap files, with codes as sorted keys.
+#[derive(Serialize)]
+struct CharmapDump {
+    char_map: BTreeMap<String, CharEntryDump>,
+    command_map: BTreeMap<String, String>,
+    control_codes: BTreeMap<&'static str, String>,
+    crypto: BTreeMap<&'static str, u32>,
+    special_byte_prefixes: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct CharEntryDump {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    char: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
+}
+
+/// Print the charmap that is actually used after reading the file as JSON, sorted by code.
+/// Aliases are listed only if they survived, so conflicting or malformed ones are left out.
+pub fn print_charmap_dump(charmap: &charmap::Charmap) -> Result<(), Box<dyn std::error::Error>> {
+    let mut char_map: BTreeMap<String, CharEntryDump> = BTreeMap::new();
+    for (code, ch) in &charmap.decode_map {
+        char_map.entry(format!("{:04X}", code)).or_default().char = Some(ch.clone());
+    }
+    // Everything in the encode map that isn't the character of its code is an alias
+    for (text, code) in &charmap.encode_map {
+        if charmap.decode_map.get(code) != Some(text) {
+            let entry = char_map.entry(format!("{:04X}", code)).or_default();
+            entry.aliases.push(text.clone());
+        }
+    }
+    for entry in char_map.values_mut() {
+        entry.aliases.sort();
+    }
+
+    let command_map = charmap
+        .command_map
+        .iter()
+        .map(|(code, name)| (format!("{:04X}", code), name.clone()))
+        .collect();
+
+    let codes = charmap.control_codes;
+    let control_codes = BTreeMap::from([
+        ("terminator", format!("{:04X}", codes.terminator)),
+        ("command_marker", format!("{:04X}", codes.command_marker)),
+        ("trainer_name", format!("{:04X}", codes.trainer_name)),
+    ]);
+
+    let crypto = BTreeMap::from([
+        ("table_seed", charmap.crypto.table_seed),
+        ("msg_mul", charmap.crypto.msg_mul),
+        ("msg_add", charmap.crypto.msg_add as u32),
+    ]);
+
+    let dump = CharmapDump {
+        char_map,
+        command_map,
+        control_codes,
+        crypto,
+        special_byte_prefixes: charmap.special_byte_prefixes.clone(),
+    };
+    println!("{}", serde_json::to_string_pretty(&dump)?);
+
+    Ok(())
+}
+
+/// Print the commands of the charmap sorted by code as `0xHHHH = NAME`, only those whose name
+/// contains `filter` ignoring case if one is given.
+pub fn print_commands(charmap: &charmap::Charmap, filter: Option<&str>) {
+    let filter = filter.map(str::to_lowercase);
+    for (code, name) in charmap.sorted_commands() {
+        if filter
+            .as_ref()
+            .is_none_or(|filter| name.to_lowercase().contains(filter.as_str()))
+        {
+            println!("0x{:04X} = {}", code, name);
+        }
+    }
+}
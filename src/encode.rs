@@ -1,39 +1,76 @@
-use byteorder::{LittleEndian, WriteBytesExt};
+use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
-use serde_derive::Deserialize;
-use serde_json;
-use std::collections::HashMap;
-use std::io::Cursor;
-use std::mem::size_of;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Cursor, Read};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 
-use crate::charmap;
-
-struct MessageTableEntry {
-    offset: u32,
-    length: u32,
-}
-
-#[derive(Deserialize)]
-struct JsonMessage {
-    #[allow(dead_code)]
-    id: String,
+use crate::{
+    archive::{self, Endian, MessageTableEntry},
+    charmap,
+    crypto::{self, CryptoParams},
+    csv,
+    error::{ChatotError, ParseNumberError},
+    log,
+};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JsonMessage {
+    pub id: String,
     #[serde(flatten)]
-    lang_message: HashMap<String, MessageContent>,
+    pub lang_message: HashMap<String, MessageContent>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(untagged)]
-enum MessageContent {
+pub(crate) enum MessageContent {
     Single(String),
     Multi(Vec<String>),
 }
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JsonInput {
+    /// Output name when the archive is part of a file holding several archives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    pub key: u16,
+    pub messages: Vec<JsonMessage>,
+}
+
+/// A JSON file holding several archives, each with its own key and messages.
 #[derive(Deserialize)]
-struct JsonInput {
-    key: u16,
-    messages: Vec<JsonMessage>,
+struct JsonMultiInput {
+    archives: Vec<JsonInput>,
+}
+
+/// Only checks whether a JSON file holds several archives, so the actual layout can then be
+/// parsed with error messages pointing into that layout.
+#[derive(Deserialize)]
+struct JsonLayout {
+    #[serde(default)]
+    archives: Option<serde::de::IgnoredAny>,
+}
+
+/// Encoded archive with its output name, which is only set for files holding several archives.
+struct NamedArchive {
+    name: Option<String>,
+    data: Vec<u8>,
+    /// Null codes inserted in place of text that couldn't be encoded
+    null_codes: usize,
+    /// Warnings about the encoded text, reported once the whole batch is encoded
+    warnings: Vec<ErrorFormat>,
+}
+
+/// Path of a written archive with the digest to print, if any.
+type WrittenArchive = (PathBuf, Option<String>);
+
+/// Key and messages of one archive read from a JSON file.
+pub(crate) struct JsonArchive {
+    /// Output name if the file holds several archives, either the `archive` field or the index
+    pub name: Option<String>,
+    pub key: u16,
+    pub messages: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -43,13 +80,205 @@ pub struct ErrorFormat {
     pub err_msg: String,
     pub span: Range<usize>,
     pub file: Option<PathBuf>,
+    /// Index of the message in its archive, if it's part of one.
+    pub message_index: Option<usize>,
+    pub kind: WarningKind,
+}
+
+/// What a warning or error about encoded text is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// Text that can't be encoded, handled by the `--on-unknown` policy
+    Unencodable,
+    /// A command name or special byte that doesn't match the charmap
+    Command,
+    /// A command number that can't be parsed
+    Number,
+}
+
+/// Record written for each warning with `--warnings-json`.
+#[derive(Debug, Serialize)]
+struct WarningRecord {
+    file: Option<PathBuf>,
+    message_index: Option<usize>,
+    kind: WarningKind,
+    detail: String,
+}
+
+/// Print the warnings of a batch, or with `--warnings-json` write them as one array of records to
+/// stderr or the `--warnings-file`.
+fn report_warnings(
+    warnings: Vec<ErrorFormat>,
+    settings: &crate::Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !settings.warnings_json && settings.warnings_file.is_none() {
+        for warning in warnings {
+            log::warning!("{warning}");
+        }
+        return Ok(());
+    }
+
+    let records: Vec<WarningRecord> = warnings
+        .into_iter()
+        .map(|warning| WarningRecord {
+            file: warning.file,
+            message_index: warning.message_index,
+            kind: warning.kind,
+            detail: warning.err_msg,
+        })
+        .collect();
+    let json = serde_json::to_string_pretty(&records)? + "\n";
+    match &settings.warnings_file {
+        Some(path) => archive::write_file_atomic(path, json.as_bytes())
+            .map_err(|e| format!("Failed to write warnings to {:?}: {}", path, e))?,
+        None => eprint!("{json}"),
+    }
+    Ok(())
+}
+
+/// Move the warnings out of the archives encoded from one file.
+fn take_warnings(encoded_archives: &mut [NamedArchive]) -> Vec<ErrorFormat> {
+    encoded_archives
+        .iter_mut()
+        .flat_map(|encoded| std::mem::take(&mut encoded.warnings))
+        .collect()
 }
 
 pub struct DiagnosticContext<'a> {
     pub source: &'a str,
     pub file: Option<&'a Path>,
+    /// Index of the message in its archive, if it's part of one.
+    pub message_index: Option<usize>,
     /// Byte range of the current issue within `source`.
     pub span: Range<usize>,
+    /// What to do with text that can't be encoded.
+    pub on_unknown: OnUnknown,
+}
+
+/// Policy for characters, aliases, escapes and commands that can't be encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnUnknown {
+    /// Warn and insert a null code in place of the unknown text
+    #[default]
+    Null,
+    /// Abort encoding with an error
+    Error,
+    /// Warn and leave the unknown text out
+    Skip,
+}
+
+/// Options shared by everything that encodes messages into an archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// Parse commands in the format of the "msgenc" tool
+    pub msgenc_format: bool,
+    /// Byte order of the written archive
+    pub endian: Endian,
+    /// What to do with text that can't be encoded
+    pub on_unknown: OnUnknown,
+    /// Boundary the data of each message is padded to
+    pub align: Option<Align>,
+    /// Point messages with the same encrypted codes as an earlier one at its data
+    pub dedup: bool,
+}
+
+/// Digest printed for each written archive with `--print-hash`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HashAlgo {
+    #[default]
+    Crc32,
+    Sha256,
+}
+
+impl HashAlgo {
+    /// Lowercase hex digest of `data`.
+    pub fn digest(self, data: &[u8]) -> String {
+        match self {
+            HashAlgo::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+            HashAlgo::Sha256 => {
+                use sha2::Digest;
+                sha2::Sha256::digest(data)
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Byte boundary for `--align`. Codes are two bytes, so unpadded messages are always 2-aligned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Align {
+    #[value(name = "2")]
+    Two,
+    #[value(name = "4")]
+    Four,
+}
+
+impl Align {
+    /// Size of the boundary in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            Align::Two => 2,
+            Align::Four => 4,
+        }
+    }
+}
+
+impl DiagnosticContext<'_> {
+    /// Diagnostic for the current span.
+    pub fn error_format(&self, kind: WarningKind, err_msg: String) -> ErrorFormat {
+        ErrorFormat {
+            source: self.source.to_string(),
+            err_msg,
+            span: self.span.clone(),
+            file: self.file.map(|p| p.to_path_buf()),
+            message_index: self.message_index,
+            kind,
+        }
+    }
+
+    /// Apply the `on_unknown` policy to text at the current span that can't be encoded.
+    /// Returns the code to insert in its place, if any.
+    fn unencodable(
+        &self,
+        warnings: &mut Vec<ErrorFormat>,
+        err_msg: String,
+    ) -> Result<Option<u16>, ChatotError> {
+        match self.on_unknown {
+            OnUnknown::Null => {
+                let err_msg = format!("{err_msg}. Inserting null code.");
+                warnings.push(self.error_format(WarningKind::Unencodable, err_msg));
+                Ok(Some(0))
+            }
+            OnUnknown::Skip => {
+                let err_msg = format!("{err_msg}. Skipping.");
+                warnings.push(self.error_format(WarningKind::Unencodable, err_msg));
+                Ok(None)
+            }
+            OnUnknown::Error => Err(ChatotError::Unencodable(
+                self.error_format(WarningKind::Unencodable, err_msg),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = self
+            .file
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "(none)".to_string());
+        write!(
+            f,
+            "{}\nFile: {}\nIn message:\n{}",
+            self.err_msg,
+            file,
+            self.span_marker()
+        )
+    }
 }
 
 impl ErrorFormat {
@@ -74,13 +303,26 @@ impl ErrorFormat {
 #[allow(dead_code)]
 pub fn validate_message(charmap: Option<&charmap::Charmap>, message: &str) -> Vec<ErrorFormat> {
     let charmap = charmap.unwrap_or(charmap::get_default_charmap());
+    message_warnings(charmap, message, false, None)
+}
+
+/// Every warning encoding `message` would produce, without printing any of them.
+pub(crate) fn message_warnings(
+    charmap: &charmap::Charmap,
+    message: &str,
+    msgenc_format: bool,
+    file: Option<&Path>,
+) -> Vec<ErrorFormat> {
     let mut warnings = Vec::new();
     let mut ctx = DiagnosticContext {
         source: message,
-        file: None,
+        file,
+        message_index: None,
         span: 0..0,
+        on_unknown: OnUnknown::Null,
     };
-    encode_string_to_message(charmap, message, false, &mut warnings, &mut ctx);
+    // The null policy only collects warnings, so encoding can't fail here
+    let _ = encode_string_to_message(charmap, message, msgenc_format, &mut warnings, &mut ctx);
     warnings
 }
 
@@ -90,214 +332,972 @@ pub fn encode_texts(
     destination: &crate::BinarySource,
     settings: &crate::Settings,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // Run everything inside a dedicated pool when the thread count is capped
+    if let Some(threads) = settings.threads {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+        let settings = crate::Settings {
+            threads: None,
+            ..settings.clone()
+        };
+        pool.install(|| {
+            encode_texts(charmap, source, destination, &settings).map_err(|e| e.to_string())
+        })?;
+        return Ok(());
+    }
+
+    // Plaintext archives use all-zero keys, which turns every XOR into a no-op
+    if settings.no_encrypt && charmap.crypto != CryptoParams::PLAINTEXT {
+        let charmap = charmap::Charmap {
+            crypto: CryptoParams::PLAINTEXT,
+            ..charmap.clone()
+        };
+        return encode_texts(&charmap, source, destination, settings);
+    }
+
+    // Messages are encrypted with their index, so identical text only gives identical codes
+    // without encryption or with `// Index:` overrides
+    if settings.dedup && charmap.crypto != CryptoParams::PLAINTEXT {
+        log::warning!(
+            "--dedup rarely finds anything to share in encrypted archives, since every message is encrypted with its own index, use it with --no-encrypt"
+        );
+    }
+
+    let start = std::time::Instant::now();
+
+    if source.stdin {
+        let Some([archive_path]) = destination.archive.as_deref() else {
+            return Err("Encoding from stdin requires exactly one archive".into());
+        };
+        let mut text_bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut text_bytes)?;
+        let text_content = settings
+            .encoding
+            .decode(&text_bytes)
+            .map_err(|e| format!("Failed to read text from stdin: {}", e))?;
+        let text_path = Path::new("<stdin>");
+        let mut encoded_archives = encode_content(charmap, &text_content, text_path, settings)?;
+        report_warnings(take_warnings(&mut encoded_archives), settings)?;
+        let stats = encoded_stats(&encoded_archives, settings.endian);
+
+        if !settings.dry_run {
+            let written = write_encoded_archives(
+                encoded_archives,
+                archive_path,
+                destination,
+                text_path,
+                settings,
+            )?;
+            for (archive_path, hash) in written {
+                if let Some(hash) = hash {
+                    println!("{}: {}", archive_path.display(), hash);
+                }
+            }
+        }
+        if settings.stats {
+            stats.print(start.elapsed());
+        }
+        return check_null_codes(&stats, settings);
+    }
+
     // Get list of text files
     let text_files = if let Some(files) = &source.txt {
         files.clone()
     } else if let Some(dir) = &source.text_dir {
-        // Read all files from directory
-        std::fs::read_dir(dir)?
-            .filter_map(|entry| entry.ok())
-            .map(|entry| entry.path())
-            .collect()
+        archive::read_dir_sorted(
+            dir,
+            &archive::FileFilter::from_settings(settings),
+            settings.recursive,
+            settings.strict,
+        )?
     } else {
         return Err("No text source specified".into());
     };
 
+    if let Some(container_path) = &destination.container {
+        let stats = encode_container(charmap, &text_files, container_path, settings)?;
+        if settings.stats {
+            stats.print(start.elapsed());
+        }
+        return check_null_codes(&stats, settings);
+    }
+
     // Get list of archive files
     let archive_files = if let Some(files) = &destination.archive {
         files.clone()
     } else if let Some(dir) = &destination.archive_dir {
         // Create vector of archive file paths which will be created when writing
-        text_files
+        let archive_files: Vec<_> = text_files
             .iter()
-            .map(|text_path| {
-                let file_stem = text_path
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("output");
-                dir.join(format!("{}", file_stem))
+            .enumerate()
+            .map(|(index, text_path)| {
+                let name = match &settings.name_pattern {
+                    Some(pattern) => {
+                        archive::expand_name_pattern(pattern, text_path, &settings.lang, index)
+                    }
+                    None => text_path
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .unwrap_or("output")
+                        .to_string(),
+                };
+                archive::output_dir(dir, text_path, source.text_dir.as_deref()).join(name)
             })
-            .collect()
+            .collect();
+        archive::check_unique_outputs(&text_files, &archive_files)?;
+        archive_files
     } else {
         return Err("No archive destination specified".into());
     };
 
+    // Zipping would silently drop whatever doesn't have a counterpart
+    if text_files.len() != archive_files.len() {
+        return Err(format!(
+            "{} inputs but {} outputs specified",
+            text_files.len(),
+            archive_files.len()
+        )
+        .into());
+    }
+
     // Open and encode each text file in parallel
-    let text_archive_pairs: Vec<_> = text_files
-        .into_iter()
-        .zip(archive_files.into_iter())
-        .collect();
+    let text_archive_pairs: Vec<_> = text_files.into_iter().zip(archive_files).collect();
 
-    let results: Vec<Result<(), String>> = text_archive_pairs
+    let progress = archive::progress_bar(text_archive_pairs.len(), settings.progress);
+
+    // Each closure returns the written archives with their digests and the warnings of the file,
+    // printed in order after the collect
+    type FileResult = (Vec<WrittenArchive>, archive::BatchStats, Vec<ErrorFormat>);
+    let results: Vec<Result<FileResult, String>> = text_archive_pairs
         .par_iter()
         .map(|(text_path, archive_path)| {
-            // Check if newer_only setting is enabled and skip if destination is newer
-            if settings.newer_only {
-                if text_path.exists() && archive_path.exists() {
-                    let archive_metadata = std::fs::metadata(archive_path).map_err(|e| {
-                        format!(
-                            "Failed to get metadata for archive {:?}: {}",
-                            archive_path, e
-                        )
-                    })?;
-                    let text_metadata = std::fs::metadata(text_path).map_err(|e| {
-                        format!(
-                            "Failed to get metadata for text file {:?}: {}",
-                            text_path, e
-                        )
-                    })?;
-                    let archive_modified = archive_metadata.modified().map_err(|e| {
-                        format!(
-                            "Failed to get modified time for archive {:?}: {}",
-                            archive_path, e
-                        )
-                    })?;
-                    let text_modified = text_metadata.modified().map_err(|e| {
-                        format!(
-                            "Failed to get modified time for text file {:?}: {}",
-                            text_path, e
-                        )
-                    })?;
-                    if archive_modified >= text_modified {
-                        #[cfg(debug_assertions)]
-                        println!(
-                            "Skipping decoding of {:?} as destination {:?} is newer",
-                            archive_path, text_path
-                        );
-                        return Ok(());
-                    }
-                }
-            }
-
-            #[cfg(debug_assertions)]
-            println!("Encoding text: {:?} -> {:?}", text_path, archive_path);
-
-            let text_content = std::fs::read_to_string(text_path)
-                .map_err(|e| format!("Failed to read text {:?}: {}", text_path, e))?;
-            let encoded_data = if settings.json {
-                encode_json(&charmap, &text_content, &settings.lang, Some(&text_path))
-                    .map_err(|e| format!("Failed to encode JSON {:?}: {}", text_path, e))?
-            } else {
-                encode_text(
-                    &charmap,
-                    &text_content,
-                    settings.msgenc_format,
-                    Some(&text_path),
-                )
-                .map_err(|e| format!("Failed to encode text {:?}: {}", text_path, e))?
-            };
-            std::fs::write(archive_path, encoded_data)
-                .map_err(|e| format!("Failed to write archive {:?}: {}", archive_path, e))?;
-
-            if settings.newer_only {
-                // Update timestamp on source text file to match destination archive
+            // Check if newer_only setting is enabled and skip if destination is newer, unless forced
+            if settings.newer_only && !settings.force && text_path.exists() && archive_path.exists()
+            {
                 let archive_metadata = std::fs::metadata(archive_path).map_err(|e| {
                     format!(
                         "Failed to get metadata for archive {:?}: {}",
                         archive_path, e
                     )
                 })?;
-                let modified_time = archive_metadata.modified().map_err(|e| {
+                let text_metadata = std::fs::metadata(text_path).map_err(|e| {
+                    format!(
+                        "Failed to get metadata for text file {:?}: {}",
+                        text_path, e
+                    )
+                })?;
+                let archive_modified = archive_metadata.modified().map_err(|e| {
                     format!(
                         "Failed to get modified time for archive {:?}: {}",
                         archive_path, e
                     )
                 })?;
-                let text_file = std::fs::File::open(text_path)
-                    .map_err(|e| format!("Failed to open text file {:?}: {}", text_path, e))?;
-                text_file.set_modified(modified_time).map_err(|e| {
+                let text_modified = text_metadata.modified().map_err(|e| {
                     format!(
-                        "Failed to update modified time for text file {:?}: {}",
+                        "Failed to get modified time for text file {:?}: {}",
                         text_path, e
                     )
                 })?;
+                if archive_modified >= text_modified {
+                    log::verbose!(
+                        "Skipping encoding of {:?} as destination {:?} is newer",
+                        text_path,
+                        archive_path
+                    );
+                    let skipped = archive::BatchStats {
+                        skipped: 1,
+                        ..Default::default()
+                    };
+                    return Ok((Vec::new(), skipped, Vec::new()));
+                }
             }
 
-            Ok(())
+            log::verbose!("Encoding text: {:?} -> {:?}", text_path, archive_path);
+
+            let text_content = archive::read_text_file(text_path, settings.encoding)
+                .map_err(|e| format!("Failed to read text {:?}: {}", text_path, e))?;
+            let mut encoded_archives = encode_content(charmap, &text_content, text_path, settings)?;
+            let warnings = take_warnings(&mut encoded_archives);
+            let stats = encoded_stats(&encoded_archives, settings.endian);
+
+            // Everything has been encoded at this point, a dry run stops before touching any files
+            if settings.dry_run {
+                return Ok((Vec::new(), stats, warnings));
+            }
+
+            let written = write_encoded_archives(
+                encoded_archives,
+                archive_path,
+                destination,
+                text_path,
+                settings,
+            )?;
+
+            // Update timestamp on source text file to match destination archive. Sources may
+            // be read-only, so this only warns instead of failing the whole batch.
+            if settings.sync_mtime
+                && let Some((archive_path, _)) = written.last()
+                && let Err(e) = archive::copy_modified_time(archive_path, text_path)
+            {
+                log::warning!(
+                    "Failed to update modified time for text file {:?}: {}",
+                    text_path,
+                    e
+                );
+            }
+
+            Ok((written, stats, warnings))
         })
+        .progress_with(progress.clone())
         .collect();
+    progress.finish();
 
-    // Check for errors
+    // Report the warnings of every file that was encoded, even if another one failed
+    let mut stats = archive::BatchStats::default();
+    let mut warnings = Vec::new();
+    let mut first_error = None;
     for result in results {
-        result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+        match result {
+            Ok((written, file_stats, file_warnings)) => {
+                if first_error.is_none() {
+                    for (archive_path, hash) in written {
+                        if let Some(hash) = hash {
+                            println!("{}: {}", archive_path.display(), hash);
+                        }
+                    }
+                }
+                stats += file_stats;
+                warnings.extend(file_warnings);
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    report_warnings(warnings, settings)?;
+    if let Some(e) = first_error {
+        return Err(e.into());
+    }
+
+    if settings.stats {
+        stats.print(start.elapsed());
+    }
+
+    check_null_codes(&stats, settings)
+}
+
+/// Counts for `--stats` of the archives encoded from one text file.
+fn encoded_stats(encoded_archives: &[NamedArchive], endian: Endian) -> archive::BatchStats {
+    let mut stats = archive::BatchStats {
+        processed: 1,
+        ..Default::default()
+    };
+    for encoded in encoded_archives {
+        // The message count is the first value of the header
+        stats.messages += endian.read_u16(&mut encoded.data.as_slice()).unwrap_or(0) as usize;
+        stats.bytes += encoded.data.len() as u64;
+        stats.null_codes += encoded.null_codes;
     }
+    stats
+}
 
+/// Report the null codes inserted over a whole batch, which otherwise encodes successfully with
+/// holes in its messages. Fails with `--strict`, so CI runs catch them.
+fn check_null_codes(
+    stats: &archive::BatchStats,
+    settings: &crate::Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if stats.null_codes == 0 {
+        return Ok(());
+    }
+    let message = format!(
+        "{} null code(s) were inserted in place of text that couldn't be encoded",
+        stats.null_codes
+    );
+    if settings.strict {
+        return Err(message.into());
+    }
+    log::warning!("{}", message);
     Ok(())
 }
 
+/// Write the archives encoded from one text file and return their paths with the digest to
+/// print, if any. A single archive is written to `archive_path`, the archives of a JSON file
+/// holding several of them are named after their `archive` field in the archive directory.
+fn write_encoded_archives(
+    encoded_archives: Vec<NamedArchive>,
+    archive_path: &Path,
+    destination: &crate::BinarySource,
+    text_path: &Path,
+    settings: &crate::Settings,
+) -> Result<Vec<WrittenArchive>, String> {
+    let mut written = Vec::with_capacity(encoded_archives.len());
+    for NamedArchive {
+        name,
+        data: encoded_data,
+        ..
+    } in encoded_archives
+    {
+        let archive_path = match (name, &destination.archive_dir) {
+            (None, _) => archive_path.to_path_buf(),
+            // Next to the derived archive path, which is in a subdirectory of a recursive run
+            (Some(name), Some(_)) => archive_path.with_file_name(name),
+            (Some(_), None) => {
+                return Err(format!(
+                    "JSON {:?} holds several archives, which requires an archive directory",
+                    text_path
+                ));
+            }
+        };
+
+        if settings.recursive {
+            archive::create_parent_dir(&archive_path)
+                .map_err(|e| format!("Failed to create directory for {:?}: {}", archive_path, e))?;
+        }
+        archive::write_archive_file(&archive_path, &encoded_data)
+            .map_err(|e| format!("Failed to write archive {:?}: {}", archive_path, e))?;
+
+        let hash = settings
+            .print_hash
+            .then(|| settings.hash_algo.digest(&encoded_data));
+        written.push((archive_path, hash));
+    }
+
+    Ok(written)
+}
+
+/// Encode text files into one container with [`archive::pack_container`]. The archives are
+/// packed in the order of the files, JSON files holding several archives add all of them.
+fn encode_container(
+    charmap: &charmap::Charmap,
+    text_files: &[PathBuf],
+    container_path: &Path,
+    settings: &crate::Settings,
+) -> Result<archive::BatchStats, Box<dyn std::error::Error>> {
+    let progress = archive::progress_bar(text_files.len(), settings.progress);
+
+    let results: Vec<Result<Vec<NamedArchive>, String>> = text_files
+        .par_iter()
+        .map(|text_path| {
+            log::verbose!("Encoding text: {:?} -> {:?}", text_path, container_path);
+
+            let text_content = archive::read_text_file(text_path, settings.encoding)
+                .map_err(|e| format!("Failed to read text {:?}: {}", text_path, e))?;
+            encode_content(charmap, &text_content, text_path, settings)
+        })
+        .progress_with(progress.clone())
+        .collect();
+    progress.finish();
+
+    let mut archives = Vec::new();
+    let mut stats = archive::BatchStats::default();
+    let mut warnings = Vec::new();
+    let mut first_error = None;
+    for result in results {
+        match result {
+            Ok(mut encoded_archives) => {
+                warnings.extend(take_warnings(&mut encoded_archives));
+                stats += encoded_stats(&encoded_archives, settings.endian);
+                archives.extend(encoded_archives.into_iter().map(|encoded| encoded.data));
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    report_warnings(warnings, settings)?;
+    if let Some(e) = first_error {
+        return Err(e.into());
+    }
+
+    let container = archive::pack_container(&archives, settings.endian)
+        .map_err(|e| format!("Failed to pack container {:?}: {}", container_path, e))?;
+    if !settings.dry_run {
+        archive::write_archive_file(container_path, &container)
+            .map_err(|e| format!("Failed to write container {:?}: {}", container_path, e))?;
+        if settings.print_hash {
+            let hash = settings.hash_algo.digest(&container);
+            println!("{}: {}", container_path.display(), hash);
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Encode the content of one text or JSON file according to the settings, returning each
+/// archive with its output name if the file holds several archives.
+/// `text_path` is only used to name the file in errors and warnings.
+fn encode_content(
+    charmap: &charmap::Charmap,
+    text_content: &str,
+    text_path: &Path,
+    settings: &crate::Settings,
+) -> Result<Vec<NamedArchive>, String> {
+    let opts = EncodeOptions {
+        msgenc_format: settings.msgenc_format,
+        endian: settings.endian,
+        on_unknown: settings.on_unknown,
+        align: settings.align,
+        dedup: settings.dedup,
+    };
+    if settings.json {
+        encode_json(
+            charmap,
+            text_content,
+            &JsonMessageOptions::from_settings(settings),
+            opts,
+            Some(text_path),
+        )
+        .map_err(|e| format!("Failed to encode JSON {:?}: {}", text_path, e))
+    } else if settings.csv {
+        csv::parse_csv_messages(text_content)
+            .and_then(|(key, messages)| {
+                encode_messages_counted(
+                    charmap,
+                    key,
+                    &messages,
+                    &BTreeMap::new(),
+                    opts,
+                    Some(text_path),
+                )
+            })
+            .map(|encoded| vec![encoded])
+            .map_err(|e| format!("Failed to encode CSV {:?}: {}", text_path, e))
+    } else {
+        encode_text(
+            charmap,
+            text_content,
+            TextLayout::from_settings(settings),
+            opts,
+            Some(text_path),
+        )
+        .map(|encoded| vec![encoded])
+        .map_err(|e| format!("Failed to encode text {:?}: {}", text_path, e))
+    }
+}
+
 fn encode_text(
     charmap: &charmap::Charmap,
     text: &str,
-    msgenc_format: bool,
+    layout: TextLayout,
+    opts: EncodeOptions,
     file: Option<&Path>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut key = 0u16;
+) -> Result<NamedArchive, ChatotError> {
+    let parsed = parse_text_messages(text, layout)?;
+
+    encode_messages_counted(
+        charmap,
+        parsed.key,
+        &parsed.messages,
+        &parsed.index_overrides,
+        opts,
+        file,
+    )
+}
+
+/// Stands for the next `// Trainer name:` line in messages decoded with `--expand-trainer-names`.
+pub(crate) const TRAINER_NAME_PLACEHOLDER: &str = "{TRAINER_NAME}";
+/// Comment line holding a trainer name as plain text, written before its message.
+pub(crate) const TRAINER_NAME_PREFIX: &str = "// Trainer name: ";
+/// Start of the comment ending a text file decoded with `--limit`, which is missing messages.
+pub(crate) const TRUNCATED_PREFIX: &str = "// ... truncated (";
+
+/// How messages are told apart in plain text files, the same for decoding and encoding.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TextLayout<'a> {
+    /// Only CRLF ends a line, a bare CR or LF is part of the message
+    pub keep_crlf: bool,
+    /// Ends every line instead of LF or CRLF
+    pub separator: Option<&'a str>,
+    /// Every message follows a `--- message N ---` line and runs up to the next one
+    pub markers: bool,
+}
+
+impl<'a> TextLayout<'a> {
+    pub fn from_settings(settings: &'a crate::Settings) -> Self {
+        TextLayout {
+            keep_crlf: settings.keep_crlf,
+            separator: settings.message_separator.as_deref(),
+            markers: settings.message_markers,
+        }
+    }
+
+    /// Written after every line of a decoded text file.
+    pub fn line_ending(&self) -> &'a str {
+        match self.separator {
+            Some(separator) => separator,
+            None if self.keep_crlf => "\r\n",
+            None => "\n",
+        }
+    }
+}
+
+/// Line starting a message in text files written with `--message-markers`.
+pub(crate) fn message_marker(index: usize) -> String {
+    format!("--- message {} ---", index)
+}
+
+fn is_message_marker(line: &str) -> bool {
+    line.strip_prefix("--- message ")
+        .and_then(|rest| rest.strip_suffix(" ---"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Parse the value of `--message-separator`, where `\n`, `\r`, `\t` and `\\` stand for the
+/// characters they do in messages.
+pub fn parse_separator(value: &str) -> Result<String, String> {
+    let mut separator = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            separator.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => separator.push('\n'),
+            Some('r') => separator.push('\r'),
+            Some('t') => separator.push('\t'),
+            Some('\\') => separator.push('\\'),
+            Some(other) => return Err(format!("unknown escape sequence '\\{}'", other)),
+            None => return Err("separator ends with a lone '\\'".to_string()),
+        }
+    }
+    if separator.is_empty() {
+        return Err("separator can't be empty".to_string());
+    }
+    Ok(separator)
+}
+
+/// Key, messages and `// Index:` directives of a plain text file.
+pub(crate) struct TextMessages {
+    pub key: u16,
+    pub messages: Vec<String>,
+    /// Index used for encrypting the message at each position instead of its own, counting from 0
+    pub index_overrides: BTreeMap<usize, u16>,
+}
+
+/// Split a plain text file into its key and messages, one message per line. A `// Index: N`
+/// line makes the next message be encrypted as if it were message N of the archive, and
+/// `// Trainer name:` lines fill the `{TRAINER_NAME}` placeholders of the next message in order.
+pub(crate) fn parse_text_messages(
+    text: &str,
+    layout: TextLayout,
+) -> Result<TextMessages, ChatotError> {
+    // Text files saved by Windows editors often start with a UTF-8 BOM (U+FEFF). Trim it so
+    // the key line is still recognized and the BOM isn't encoded as an unknown character.
+    let text = text.trim_start_matches('\u{FEFF}');
+    if layout.markers {
+        return parse_marked_messages(text, layout.keep_crlf);
+    }
+    let mut key = None;
     let mut messages: Vec<String> = Vec::new();
+    let mut index_overrides = BTreeMap::new();
+    let mut trainer_names: Vec<&str> = Vec::new();
+
+    // By default both LF and CRLF end a line. With keep_crlf only CRLF does, so a bare CR or LF
+    // stays part of the message it appears in. A custom separator is the only line ending.
+    let lines: Vec<&str> = if let Some(separator) = layout.separator {
+        text.split_terminator(separator).collect()
+    } else if layout.keep_crlf {
+        text.split_terminator("\r\n").collect()
+    } else {
+        text.lines().collect()
+    };
 
-    for line in text.lines() {
-        // First line is key (// Key: XXXX)
+    for (number, line) in lines.into_iter().enumerate() {
+        // Key line (// Key: XXXX) before the first message, normally the first line
         if let Some(key_str) = line.strip_prefix("// Key: ") {
-            key = parse_hex_or_decimal(key_str.trim()) as u16;
+            if !messages.is_empty() {
+                return Err(ChatotError::InvalidText {
+                    line: number + 1,
+                    reason: "key line after the first message",
+                });
+            }
+            key = Some(parse_key_line(key, key_str, number + 1)?);
             continue; // skip key line
         }
 
+        // Applies to the message on the next line that isn't a comment
+        if let Some(index_str) = line.strip_prefix("// Index: ") {
+            let index_str = index_str.trim();
+            let index = parse_number(index_str).map_err(|reason| ChatotError::InvalidIndex {
+                index: index_str.to_string(),
+                reason,
+            })?;
+            index_overrides.insert(messages.len(), index);
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix(TRAINER_NAME_PREFIX) {
+            trainer_names.push(name);
+            continue;
+        }
+
+        // Encoding a file cut short by `--limit` would drop the rest of the archive's messages
+        if line.starts_with(TRUNCATED_PREFIX) {
+            return Err(ChatotError::InvalidText {
+                line: number + 1,
+                reason: "the file was decoded with --limit and is missing messages, remove this line to encode it anyway",
+            });
+        }
+
         // Ignore comment lines
         if line.trim_start().starts_with("//") {
             continue;
         }
 
-        messages.push(line.to_string());
+        let placeholders = line.matches(TRAINER_NAME_PLACEHOLDER).count();
+        if placeholders != trainer_names.len() {
+            return Err(ChatotError::TrainerNameCount {
+                index: messages.len(),
+                names: trainer_names.len(),
+                placeholders,
+            });
+        }
+        let mut message = line.to_string();
+        for name in trainer_names.drain(..) {
+            message = message.replacen(
+                TRAINER_NAME_PLACEHOLDER,
+                &format!("{{TRAINER_NAME:{}}}", name),
+                1,
+            );
+        }
+        messages.push(message);
+    }
+
+    Ok(TextMessages {
+        key: key.unwrap_or(0),
+        messages,
+        index_overrides,
+    })
+}
+
+/// Parse the key of a `// Key:` line on line number `line`. `key` is the key of an earlier key
+/// line, if any, since a file may only set its key once.
+fn parse_key_line(key: Option<u16>, key_str: &str, line: usize) -> Result<u16, ChatotError> {
+    if key.is_some() {
+        return Err(ChatotError::InvalidText {
+            line,
+            reason: "more than one key line",
+        });
+    }
+    let key_str = key_str.trim();
+    parse_number(key_str).map_err(|reason| ChatotError::InvalidKey {
+        key: key_str.to_string(),
+        reason,
+    })
+}
+
+/// Split a text file written with `--message-markers`. Lines up to the first marker hold the key
+/// and comments, every other line belongs to the message of the marker before it, so messages
+/// may contain line breaks and lines that look like comments.
+fn parse_marked_messages(text: &str, keep_crlf: bool) -> Result<TextMessages, ChatotError> {
+    let mut key = None;
+    let mut messages: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+
+    // The line ending before the next marker separates the messages and isn't part of either
+    let finish = |message: String| {
+        let message = if keep_crlf {
+            message.strip_suffix("\r\n").unwrap_or(&message)
+        } else {
+            let message = message.strip_suffix('\n').unwrap_or(&message);
+            message.strip_suffix('\r').unwrap_or(message)
+        };
+        message.to_string()
+    };
+
+    for (number, line) in text.split_inclusive('\n').enumerate() {
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if is_message_marker(trimmed) {
+            if let Some(message) = current.take() {
+                messages.push(finish(message));
+            }
+            current = Some(String::new());
+        } else if let Some(message) = &mut current {
+            message.push_str(line);
+        } else if let Some(key_str) = trimmed.strip_prefix("// Key: ") {
+            key = Some(parse_key_line(key, key_str, number + 1)?);
+        } else if !trimmed.trim_start().starts_with("//") && !trimmed.is_empty() {
+            return Err(ChatotError::InvalidText {
+                line: number + 1,
+                reason: "text before the first message marker",
+            });
+        }
+    }
+    if let Some(message) = current {
+        messages.push(finish(message));
     }
 
-    encode_messages(charmap, key, &messages, msgenc_format, file)
+    Ok(TextMessages {
+        key: key.unwrap_or(0),
+        messages,
+        index_overrides: BTreeMap::new(),
+    })
 }
 
 fn encode_json(
     charmap: &charmap::Charmap,
     json_content: &str,
-    lang: &str,
+    json_opts: &JsonMessageOptions,
+    opts: EncodeOptions,
     file: Option<&Path>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+) -> Result<Vec<NamedArchive>, ChatotError> {
+    // Commands in JSON files always use the regular format
+    let opts = EncodeOptions {
+        msgenc_format: false,
+        ..opts
+    };
+
+    parse_json_archives(json_content, json_opts)?
+        .into_iter()
+        .map(|archive| {
+            log::verbose!(
+                "Encoding JSON with key: 0x{:04X}, messages: {}",
+                archive.key,
+                archive.messages.len()
+            );
+
+            let encoded = encode_messages_counted(
+                charmap,
+                archive.key,
+                &archive.messages,
+                &BTreeMap::new(),
+                opts,
+                file,
+            )?;
+            Ok(NamedArchive {
+                name: archive.name,
+                ..encoded
+            })
+        })
+        .collect()
+}
+
+/// How the messages of a JSON text file are picked from their languages and put in order.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct JsonMessageOptions<'a> {
+    /// Language whose text is encoded
+    pub lang: &'a str,
+    /// Languages tried in order for messages without text in `lang`
+    pub base_lang: &'a [String],
+    /// Place messages at the index given by their id instead of array order
+    pub ordered_by_id: bool,
+    /// Put between the lines of messages given as a list
+    pub multi_join: &'a str,
+}
+
+impl<'a> JsonMessageOptions<'a> {
+    pub fn from_settings(settings: &'a crate::Settings) -> Self {
+        JsonMessageOptions {
+            lang: &settings.lang,
+            base_lang: &settings.base_lang,
+            ordered_by_id: settings.ordered_by_id,
+            multi_join: &settings.multi_join,
+        }
+    }
+}
+
+/// Read the key and the messages in the requested language of every archive in a JSON text file.
+/// The file holds either a single archive or an `archives` list of them.
+pub(crate) fn parse_json_archives(
+    json_content: &str,
+    json_opts: &JsonMessageOptions,
+) -> Result<Vec<JsonArchive>, ChatotError> {
+    let (inputs, several) = parse_json_inputs(json_content)?;
+
+    inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, archive)| {
+            Ok(JsonArchive {
+                name: several.then(|| archive.archive.unwrap_or_else(|| index.to_string())),
+                key: archive.key,
+                messages: json_input_messages(archive.messages, json_opts)?,
+            })
+        })
+        .collect()
+}
+
+/// Every language any message of a JSON text file has text in, over all of its archives.
+/// Messages without the requested language fall back to the `--base-lang` chain when encoding.
+#[allow(dead_code)]
+pub fn json_languages(json_content: &str) -> Result<BTreeSet<String>, ChatotError> {
+    let (inputs, _) = parse_json_inputs(json_content)?;
+
+    Ok(inputs
+        .iter()
+        .flat_map(|archive| &archive.messages)
+        .flat_map(|message| message.lang_message.keys().cloned())
+        .collect())
+}
+
+/// Parse the archives of a JSON text file, and whether it holds an `archives` list rather than
+/// a single archive.
+fn parse_json_inputs(json_content: &str) -> Result<(Vec<JsonInput>, bool), ChatotError> {
     // Some JSON files may start with a UTF-8 BOM (U+FEFF). Trim it so
     // serde_json doesn't fail with "expected value at line 1 column 1".
     let content = json_content.trim_start_matches('\u{FEFF}');
-    let parsed: JsonInput = serde_json::from_str(content)?;
+    let layout: JsonLayout = serde_json::from_str(content)?;
 
-    let mut messages: Vec<String> = Vec::with_capacity(parsed.messages.len());
+    if layout.archives.is_none() {
+        let parsed: JsonInput = serde_json::from_str(content)?;
+        return Ok((vec![parsed], false));
+    }
+
+    let parsed: JsonMultiInput = serde_json::from_str(content)?;
+    Ok((parsed.archives, true))
+}
 
-    for msg in parsed.messages.iter() {
-        let content = msg
-            .lang_message
-            .get(lang)
-            .or_else(|| msg.lang_message.get("en_US"))
-            .ok_or_else(|| format!("Language '{}' not found in message {}", lang, msg.id))?;
+/// The text in language `lang` of each JSON message, falling back to the first language of
+/// `base_lang` the message has text in.
+/// Messages given as a list of lines are joined with `multi_join`, which is message text itself,
+/// so it can be a literal separator as well as a command like `{CLEAR, 0}`.
+fn json_input_messages(
+    json_messages: Vec<JsonMessage>,
+    json_opts: &JsonMessageOptions,
+) -> Result<Vec<String>, ChatotError> {
+    let JsonMessageOptions {
+        lang,
+        base_lang,
+        ordered_by_id,
+        multi_join,
+    } = *json_opts;
+    let json_messages: Vec<Option<JsonMessage>> = if ordered_by_id {
+        order_messages_by_id(json_messages)?
+    } else {
+        json_messages.into_iter().map(Some).collect()
+    };
+
+    let mut messages: Vec<String> = Vec::with_capacity(json_messages.len());
+
+    for msg in json_messages.iter() {
+        // Gaps between ids are filled with empty messages
+        let Some(msg) = msg else {
+            messages.push(String::new());
+            continue;
+        };
+
+        let content = match msg.lang_message.get(lang) {
+            Some(content) => content,
+            None => {
+                let (fallback, content) = base_lang
+                    .iter()
+                    .find_map(|fallback| Some((fallback, msg.lang_message.get(fallback)?)))
+                    .ok_or_else(|| ChatotError::LanguageNotFound {
+                        lang: lang.to_string(),
+                        fallbacks: base_lang.to_vec(),
+                        id: msg.id.clone(),
+                    })?;
+                log::verbose!(
+                    "Message {} has no '{}' text, falling back to '{}'",
+                    msg.id,
+                    lang,
+                    fallback
+                );
+                content
+            }
+        };
 
         let message_str = match content {
             MessageContent::Single(s) => s.clone(),
-            MessageContent::Multi(lines) => lines.join(""),
+            MessageContent::Multi(lines) => lines.join(multi_join),
         };
 
         messages.push(message_str);
     }
 
-    #[cfg(debug_assertions)]
-    println!(
-        "Encoding JSON with key: 0x{:04X}, messages: {}",
-        parsed.key,
-        messages.len()
-    );
+    Ok(messages)
+}
 
-    encode_messages(charmap, parsed.key, &messages, false, file)
+/// Place every message at the index given by its id, either a plain number or the number after
+/// the last underscore like the `msg_<name>_<index>` ids written by decode.
+/// Indices without a message are left as `None`.
+fn order_messages_by_id(
+    messages: Vec<JsonMessage>,
+) -> Result<Vec<Option<JsonMessage>>, ChatotError> {
+    let mut by_index: BTreeMap<usize, JsonMessage> = BTreeMap::new();
+    for msg in messages {
+        let number = msg.id.rsplit('_').next().unwrap_or(&msg.id);
+        let Ok(index) = number.parse::<usize>() else {
+            return Err(ChatotError::InvalidMessageId {
+                id: msg.id,
+                reason: "id does not end in a message index",
+            });
+        };
+        if by_index.contains_key(&index) {
+            return Err(ChatotError::InvalidMessageId {
+                id: msg.id,
+                reason: "another message has the same index",
+            });
+        }
+        by_index.insert(index, msg);
+    }
+
+    let message_count = by_index.keys().next_back().map_or(0, |last| last + 1);
+    Ok((0..message_count)
+        .map(|index| by_index.remove(&index))
+        .collect())
 }
 
-fn encode_messages(
+/// Encode messages into an archive in memory.
+///
+/// Warnings about text that can't be encoded are printed to stderr, or returned as
+/// [`ChatotError::Unencodable`] when `opts.on_unknown` is [`OnUnknown::Error`].
+#[allow(dead_code)]
+pub fn encode_archive(
     charmap: &charmap::Charmap,
     key: u16,
     messages: &[String],
-    msgenc_format: bool,
+    opts: EncodeOptions,
+) -> Result<Vec<u8>, ChatotError> {
+    encode_messages(charmap, key, messages, opts, None)
+}
+
+/// Encode messages into an archive and print any warnings, naming `file` in them.
+pub(crate) fn encode_messages(
+    charmap: &charmap::Charmap,
+    key: u16,
+    messages: &[String],
+    opts: EncodeOptions,
     file: Option<&Path>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut message_index = 0usize;
+) -> Result<Vec<u8>, ChatotError> {
+    let encoded = encode_messages_counted(charmap, key, messages, &BTreeMap::new(), opts, file)?;
+    for warning in encoded.warnings {
+        log::warning!("{warning}");
+    }
+    Ok(encoded.data)
+}
+
+/// Like [`encode_messages`], but encrypt the messages at the positions in `index_overrides` and
+/// their table entries as if they were the message with the given index instead. The warnings
+/// are returned with the archive instead of printed, along with the number of null codes
+/// inserted in place of text that couldn't be encoded.
+fn encode_messages_counted(
+    charmap: &charmap::Charmap,
+    key: u16,
+    messages: &[String],
+    index_overrides: &BTreeMap<usize, u16>,
+    opts: EncodeOptions,
+    file: Option<&Path>,
+) -> Result<NamedArchive, ChatotError> {
+    // The header stores the message count in 16 bits
+    if messages.len() > u16::MAX as usize {
+        return Err(ChatotError::TooManyMessages {
+            count: messages.len(),
+        });
+    }
+
+    // Encryption counts messages from 1
+    let key_indices: Vec<u16> = (0..messages.len())
+        .map(|i| {
+            index_overrides
+                .get(&i)
+                .map_or(i as u16, |&index| index)
+                .wrapping_add(1)
+        })
+        .collect();
 
     // Create message table
     let mut message_table: Vec<MessageTableEntry> = Vec::new();
@@ -305,100 +1305,124 @@ fn encode_messages(
     // Collect encoded messages
     let mut encoded_messages = Vec::new();
     let mut all_warnings = Vec::new();
+    let mut shared_messages: HashMap<Vec<u16>, MessageTableEntry> = HashMap::new();
 
-    for message in messages {
-        // Start from message index 1
-        message_index += 1;
+    // Message data starts after the header and the table
+    let table_size = messages.len() * archive::TABLE_ENTRY_SIZE;
+    let header_size = 4; // 2 bytes for message count + 2 bytes for key
+    let data_start = (table_size + header_size) as u32;
 
+    for (index, (message, &message_index)) in messages.iter().zip(&key_indices).enumerate() {
         let mut ctx = DiagnosticContext {
             source: message.as_str(),
             file,
+            message_index: Some(index),
             span: 0..0,
+            on_unknown: opts.on_unknown,
         };
-        let message_codes =
-            encode_string_to_message(charmap, message, msgenc_format, &mut all_warnings, &mut ctx);
-        let mut encrypted_codes = encrypt_message(&message_codes, message_index as u16);
+        let message_codes = encode_string_to_message(
+            charmap,
+            message,
+            opts.msgenc_format,
+            &mut all_warnings,
+            &mut ctx,
+        )?;
+        let mut encrypted_codes = encrypt_message(&message_codes, message_index, &charmap.crypto);
+
+        // Messages are encrypted with their index, so the codes only repeat for messages sharing
+        // a key index or in archives without encryption
+        if opts.dedup
+            && let Some(&entry) = shared_messages.get(&encrypted_codes)
+        {
+            message_table.push(entry);
+            continue;
+        }
 
-        let len = encrypted_codes.len() as u32; // length in u16 units
+        // Each message follows the previous one and any padding after it (in bytes)
+        let offset = data_start as u64 + encoded_messages.len() as u64 * 2;
 
-        // If there is a previous message, calculate offset (in bytes)
-        let offset = if message_table.is_empty() {
-            0
-        } else {
-            message_table.last().unwrap().offset + (message_table.last().unwrap().length * 2)
-        };
+        // Offsets are stored in 32 bits, so every message has to end within the first 4 GiB
+        let end = offset + encrypted_codes.len() as u64 * 2;
+        if end > u32::MAX as u64 {
+            return Err(ChatotError::ArchiveTooLarge { size: end });
+        }
+        let offset = offset as u32;
+        let len = encrypted_codes.len() as u32; // length in u16 units
 
-        message_table.push(MessageTableEntry {
+        let entry = MessageTableEntry {
             offset,
             length: len,
-        });
+        };
+        message_table.push(entry);
+        if opts.dedup {
+            shared_messages.insert(encrypted_codes.clone(), entry);
+        }
 
         // Append encrypted message to encoded data
         encoded_messages.append(&mut encrypted_codes);
+
+        // Pad with zero codes up to the next boundary, the length in the table leaves them out
+        if let Some(align) = opts.align {
+            while !(data_start as usize + encoded_messages.len() * 2).is_multiple_of(align.bytes())
+            {
+                encoded_messages.push(0);
+            }
+        }
     }
 
-    // Adjust offsets in message table to account for table itself and header
     let message_count = message_table.len();
-    let table_size = message_count * size_of::<MessageTableEntry>(); // each entry
-    let header_size = 4; // 2 bytes for message count + 2 bytes for key
-    for entry in message_table.iter_mut() {
-        entry.offset += table_size as u32 + header_size;
-    }
 
     // Create a cursor to write binary data
     let mut cursor = Cursor::new(Vec::new());
 
     // Write header
-    cursor.write_u16::<LittleEndian>(message_count as u16)?;
-    cursor.write_u16::<LittleEndian>(key)?;
+    opts.endian.write_u16(&mut cursor, message_count as u16)?;
+    opts.endian.write_u16(&mut cursor, key)?;
 
     // Write message table
     for (i, entry) in message_table.iter().enumerate() {
         // Encrypt offset and length
-        let mut local_key: u32 = 765;
-        local_key = local_key.wrapping_mul((i + 1) as u32);
-        local_key = local_key.wrapping_mul(key as u32);
-        local_key &= 0xFFFF;
-        local_key |= local_key << 16;
+        let local_key = crypto::table_key(key, key_indices[i] as u32, charmap.crypto.table_seed);
 
         let enc_offset = entry.offset ^ local_key;
         let enc_length = entry.length ^ local_key;
 
-        cursor.write_u32::<LittleEndian>(enc_offset)?;
-        cursor.write_u32::<LittleEndian>(enc_length)?;
-    }
-
-    // Write encoded messages
-    for code in encoded_messages.iter() {
-        cursor.write_u16::<LittleEndian>(*code)?;
-    }
-
-    for warning in all_warnings {
-        let file = warning
-            .file
-            .as_ref()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|| "(none)".to_string());
-        eprintln!(
-            "Warning: {}\nFile: {}\nIn message:\n{}",
-            warning.err_msg,
-            file,
-            warning.span_marker(),
-        );
+        opts.endian.write_u32(&mut cursor, enc_offset)?;
+        opts.endian.write_u32(&mut cursor, enc_length)?;
     }
 
-    Ok(cursor.into_inner())
+    // Write encoded messages
+    for code in encoded_messages.iter() {
+        opts.endian.write_u16(&mut cursor, *code)?;
+    }
+
+    // The null policy inserts one null code for every piece of text it warns about
+    let null_codes = if opts.on_unknown == OnUnknown::Null {
+        all_warnings
+            .iter()
+            .filter(|warning| warning.kind == WarningKind::Unencodable)
+            .count()
+    } else {
+        0
+    };
+
+    Ok(NamedArchive {
+        name: None,
+        data: cursor.into_inner(),
+        null_codes,
+        warnings: all_warnings,
+    })
 }
 
-fn encrypt_message(decrypted_message: &Vec<u16>, index: u16) -> Vec<u16> {
+fn encrypt_message(decrypted_message: &[u16], index: u16, crypto: &CryptoParams) -> Vec<u16> {
     let mut encrypted_message = Vec::new();
 
-    let mut current_key: u16 = (index as u32).wrapping_mul(596947) as u16;
+    let mut current_key: u16 = (index as u32).wrapping_mul(crypto.msg_mul) as u16;
 
     for &dec_char in decrypted_message {
         let enc_char = dec_char ^ current_key;
         encrypted_message.push(enc_char);
-        current_key = current_key.wrapping_add(18749);
+        current_key = current_key.wrapping_add(crypto.msg_add);
         current_key &= 0xFFFF;
     }
 
@@ -411,7 +1435,7 @@ fn encode_string_to_message(
     msgenc_format: bool,
     warnings: &mut Vec<ErrorFormat>,
     ctx: &mut DiagnosticContext<'_>,
-) -> Vec<u16> {
+) -> Result<Vec<u16>, ChatotError> {
     let mut message_codes = Vec::new();
 
     let mut chars = text.chars().peekable();
@@ -422,8 +1446,17 @@ fn encode_string_to_message(
         byte_pos += ch.len_utf8();
         let ch_str = ch.to_string();
 
+        // Try entries made of several chars first, e.g. a letter followed by a combining accent
+        if let Some((len, code)) = charmap.longest_match(&text[ch_start..]) {
+            for _ in text[byte_pos..ch_start + len].chars() {
+                chars.next();
+            }
+            byte_pos = ch_start + len;
+            message_codes.push(code);
+            continue;
+        }
         // Try single character lookup
-        if charmap.encode_map.contains_key(&ch_str) {
+        else if charmap.encode_map.contains_key(&ch_str) {
             let code = charmap.encode_map.get(&ch_str).unwrap();
             message_codes.push(*code);
             continue;
@@ -451,22 +1484,12 @@ fn encode_string_to_message(
                 continue;
             } else if found_closing {
                 ctx.span = bracket_start..byte_pos;
-                warnings.push(ErrorFormat {
-                    source: ctx.source.to_string(),
-                    err_msg: format!("unknown alias '{alias}'. Inserting null code."),
-                    span: ctx.span.clone(),
-                    file: ctx.file.map(|p| p.to_path_buf()),
-                });
+                message_codes
+                    .extend(ctx.unencodable(warnings, format!("unknown alias '{alias}'"))?);
             } else {
                 ctx.span = bracket_start..byte_pos;
-                warnings.push(ErrorFormat {
-                    source: ctx.source.to_string(),
-                    err_msg: "unmatched '[' in text. Inserting null code.".into(),
-                    span: ctx.span.clone(),
-                    file: ctx.file.map(|p| p.to_path_buf()),
-                });
+                message_codes.extend(ctx.unencodable(warnings, "unmatched '[' in text".into())?);
             }
-            message_codes.push(0);
             continue;
         }
         // Escape sequences (\xXXXX or \n, \r, etc.)
@@ -474,7 +1497,8 @@ fn encode_string_to_message(
             let escape_start = ch_start;
             if let Some(&next_ch) = chars.peek() {
                 if next_ch == 'x' {
-                    // Try to read hex code \xXXXX
+                    // Try to read hex code \xXXXX. These are always written on purpose, like
+                    // a \x0000 padding code, so a valid one never warns whatever the charmap has
                     byte_pos += next_ch.len_utf8();
                     chars.next(); // consume 'x'
                     let mut hex_str = String::new();
@@ -494,26 +1518,17 @@ fn encode_string_to_message(
                             continue;
                         } else {
                             ctx.span = escape_start..byte_pos;
-                            warnings.push(ErrorFormat {
-                                source: ctx.source.to_string(),
-                                err_msg: format!(
-                                    "invalid escape sequence '\\x{hex_str}'. Inserting null code."
-                                ),
-                                span: ctx.span.clone(),
-                                file: ctx.file.map(|p| p.to_path_buf()),
-                            });
-                            message_codes.push(0);
+                            message_codes.extend(ctx.unencodable(
+                                warnings,
+                                format!("invalid escape sequence '\\x{hex_str}'"),
+                            )?);
                             continue;
                         }
                     } else {
                         ctx.span = escape_start..byte_pos;
-                        warnings.push(ErrorFormat {
-                            source: ctx.source.to_string(),
-                            err_msg: "incomplete hex escape sequence. Inserting null code.".into(),
-                            span: ctx.span.clone(),
-                            file: ctx.file.map(|p| p.to_path_buf()),
-                        });
-                        message_codes.push(0);
+                        message_codes.extend(
+                            ctx.unencodable(warnings, "incomplete hex escape sequence".into())?,
+                        );
                         continue;
                     }
                 } else {
@@ -528,28 +1543,18 @@ fn encode_string_to_message(
                         continue;
                     } else {
                         ctx.span = escape_start..byte_pos;
-                        warnings.push(ErrorFormat {
-                            source: ctx.source.to_string(),
-                            err_msg: format!(
-                                "unknown escape sequence '{escape_seq}'. Inserting null code."
-                            ),
-                            span: ctx.span.clone(),
-                            file: ctx.file.map(|p| p.to_path_buf()),
-                        });
-                        message_codes.push(0);
+                        message_codes.extend(ctx.unencodable(
+                            warnings,
+                            format!("unknown escape sequence '{escape_seq}'"),
+                        )?);
                         continue;
                     }
                 }
             } else {
                 ctx.span = escape_start..byte_pos;
-                warnings.push(ErrorFormat {
-                    source: ctx.source.to_string(),
-                    err_msg: "incomplete escape sequence at end of text. Inserting null code."
-                        .into(),
-                    span: ctx.span.clone(),
-                    file: ctx.file.map(|p| p.to_path_buf()),
-                });
-                message_codes.push(0);
+                message_codes.extend(
+                    ctx.unencodable(warnings, "incomplete escape sequence at end of text".into())?,
+                );
                 continue;
             }
         }
@@ -576,32 +1581,20 @@ fn encode_string_to_message(
 
             if !found_closing {
                 ctx.span = command_span;
-                warnings.push(ErrorFormat {
-                    source: ctx.source.to_string(),
-                    err_msg: "unmatched '{' in text. Inserting null code.".into(),
-                    span: ctx.span.clone(),
-                    file: ctx.file.map(|p| p.to_path_buf()),
-                });
-                message_codes.push(0);
+                message_codes.extend(ctx.unencodable(warnings, "unmatched '{' in text".into())?);
                 continue;
             }
 
             if command_str.is_empty() {
                 ctx.span = command_span;
-                warnings.push(ErrorFormat {
-                    source: ctx.source.to_string(),
-                    err_msg: "empty command '{}'. Inserting null code.".into(),
-                    span: ctx.span.clone(),
-                    file: ctx.file.map(|p| p.to_path_buf()),
-                });
-                message_codes.push(0);
+                message_codes.extend(ctx.unencodable(warnings, "empty command '{}'".into())?);
                 continue;
             }
             // Special handling for TRAINER_NAME command
-            if command_str.starts_with("TRAINER_NAME:") {
-                let name_str = &command_str["TRAINER_NAME:".len()..];
+            if let Some(name_str) = command_str.strip_prefix("TRAINER_NAME:") {
                 ctx.span = command_span;
-                let name_codes = encode_trainer_name(charmap, name_str, ctx, warnings);
+                let at_end = chars.peek().is_none();
+                let name_codes = encode_trainer_name(charmap, name_str, at_end, ctx, warnings)?;
                 message_codes.extend(name_codes);
                 continue;
             }
@@ -610,17 +1603,17 @@ fn encode_string_to_message(
                 // Treat the rest of the message as trainer name
                 let name_str: String = chars.collect();
                 ctx.span = command_span;
-                let name_codes = encode_trainer_name(charmap, &name_str, ctx, warnings);
+                let name_codes = encode_trainer_name(charmap, &name_str, true, ctx, warnings)?;
                 message_codes.extend(name_codes);
                 break; // end of message
             } else if msgenc_format {
                 ctx.span = command_span;
-                let command_codes = encode_command_msgenc(charmap, &command_str, ctx, warnings);
+                let command_codes = encode_command_msgenc(charmap, &command_str, ctx, warnings)?;
                 message_codes.extend(command_codes);
                 continue;
             } else {
                 ctx.span = command_span;
-                let command_codes = encode_command(charmap, &command_str, ctx, warnings);
+                let command_codes = encode_command(charmap, &command_str, ctx, warnings)?;
                 message_codes.extend(command_codes);
                 continue;
             }
@@ -628,21 +1621,15 @@ fn encode_string_to_message(
         // Unknown character
         else {
             ctx.span = ch_start..byte_pos;
-            warnings.push(ErrorFormat {
-                source: ctx.source.to_string(),
-                err_msg: format!("unknown character '{ch}'. Inserting null code."),
-                span: ctx.span.clone(),
-                file: ctx.file.map(|p| p.to_path_buf()),
-            });
-            message_codes.push(0);
+            message_codes.extend(ctx.unencodable(warnings, format!("unknown character '{ch}'"))?);
             continue;
         }
     }
 
     // Message termination code
-    message_codes.push(0xFFFF);
+    message_codes.push(charmap.control_codes.terminator);
 
-    message_codes
+    Ok(message_codes)
 }
 
 fn encode_command(
@@ -650,7 +1637,7 @@ fn encode_command(
     command_str: &str,
     ctx: &mut DiagnosticContext<'_>,
     warnings: &mut Vec<ErrorFormat>,
-) -> Vec<u16> {
+) -> Result<Vec<u16>, ChatotError> {
     let mut command_codes = Vec::new();
 
     // Split command and arguments
@@ -658,45 +1645,29 @@ fn encode_command(
 
     // Ensure there is at least a command name and the special byte which is OR'ed with it
     if parts.len() < 2 {
-        warnings.push(ErrorFormat {
-            source: ctx.source.to_string(),
-            err_msg: format!("invalid command format '{command_str}'. Inserting null code."),
-            span: ctx.span.clone(),
-            file: ctx.file.map(|p| p.to_path_buf()),
-        });
-        command_codes.push(0);
-        return command_codes;
+        command_codes
+            .extend(ctx.unencodable(warnings, format!("invalid command format '{command_str}'"))?);
+        return Ok(command_codes);
     }
 
     // First part is command
     let command_name = parts[0];
 
-    let mut command_code = match charmap
-        .command_map
-        .iter()
-        .find(|(_, name)| *name == command_name)
-    {
-        Some((code, _)) => *code,
-        None => {
-            let code = parse_hex_or_decimal(command_name) as u16;
-            warnings.push(ErrorFormat {
-                source: ctx.source.to_string(),
-                err_msg: format!("unknown command name '{command_name}'. Using code 0x{code:04X}."),
-                span: ctx.span.clone(),
-                file: ctx.file.map(|p| p.to_path_buf()),
-            });
-            code
-        }
-    };
+    let (mut command_code, command_name) = lookup_command(charmap, command_name, ctx, warnings);
 
     // Second part is always special byte
-    let special_byte_str = parts[1];
-
-    // Allow special byte to be in hex (0xXX) or decimal
-    let special_byte = parse_hex_or_decimal(special_byte_str) as u16;
+    let special_byte = parse_command_number(parts[1], "special byte", command_str, ctx, warnings)?;
+    if special_byte != 0 && !charmap.has_special_byte(command_name) {
+        warnings.push(ctx.error_format(
+            WarningKind::Command,
+            format!(
+                "command '{command_name}' has no special byte, {special_byte} changes its code."
+            ),
+        ));
+    }
 
     // Push command marker
-    command_codes.push(0xFFFE);
+    command_codes.push(charmap.control_codes.command_marker);
 
     command_code |= special_byte;
     command_codes.push(command_code);
@@ -705,11 +1676,56 @@ fn encode_command(
     let param_len = parts.len() - 2;
     command_codes.push(param_len as u16);
 
-    for param_str in parts.iter().skip(2) {
-        let param = parse_hex_or_decimal(param_str) as u16;
+    for (index, param_str) in parts.iter().skip(2).enumerate() {
+        let param = parse_command_number(
+            param_str,
+            &format!("parameter {index}"),
+            command_str,
+            ctx,
+            warnings,
+        )?;
         command_codes.push(param);
     }
-    command_codes
+    Ok(command_codes)
+}
+
+/// Find the code of a command name and the name as written in the charmap. Names only matching
+/// when ignoring ASCII case are accepted with a warning, and unknown names are parsed as a code.
+fn lookup_command<'a>(
+    charmap: &'a charmap::Charmap,
+    command_name: &'a str,
+    ctx: &DiagnosticContext<'_>,
+    warnings: &mut Vec<ErrorFormat>,
+) -> (u16, &'a str) {
+    if let Some((code, name)) = charmap
+        .command_map
+        .iter()
+        .find(|(_, name)| *name == command_name)
+    {
+        return (*code, name);
+    }
+
+    // Several names could differ only in case, pick the lowest code so the result doesn't
+    // depend on the map's iteration order
+    if let Some((code, name)) = charmap
+        .command_map
+        .iter()
+        .filter(|(_, name)| name.eq_ignore_ascii_case(command_name))
+        .min_by_key(|(code, _)| **code)
+    {
+        warnings.push(ctx.error_format(
+            WarningKind::Command,
+            format!("command name '{command_name}' matched '{name}' ignoring case."),
+        ));
+        return (*code, name);
+    }
+
+    let code = parse_number(command_name).unwrap_or(0);
+    warnings.push(ctx.error_format(
+        WarningKind::Command,
+        format!("unknown command name '{command_name}'. Using code 0x{code:04X}."),
+    ));
+    (code, command_name)
 }
 
 fn encode_command_msgenc(
@@ -717,14 +1733,18 @@ fn encode_command_msgenc(
     command_str: &str,
     ctx: &mut DiagnosticContext<'_>,
     warnings: &mut Vec<ErrorFormat>,
-) -> Vec<u16> {
+) -> Result<Vec<u16>, ChatotError> {
     let mut command_codes = Vec::new();
 
     // Opinion: I don't understand why msgenc uses this different format for commands.
     // You could just put a comma between the command name and parameters instead of using whitespace here and ONLY here.
     // Split into two parts by finding first whitespace
     let mut parts_iter = command_str.split_whitespace();
-    let command_name = parts_iter.next().unwrap();
+    let Some(command_name) = parts_iter.next() else {
+        command_codes
+            .extend(ctx.unencodable(warnings, format!("invalid command format '{command_str}'"))?);
+        return Ok(command_codes);
+    };
 
     // Split the rest by commas and remove any empty parts
     let parts: Vec<&str> = parts_iter
@@ -732,42 +1752,23 @@ fn encode_command_msgenc(
         .filter(|s| !s.is_empty())
         .collect();
 
-    let mut command_code = match charmap
-        .command_map
-        .iter()
-        .find(|(_, name)| *name == command_name)
-    {
-        Some((code, _)) => *code,
-        None => {
-            let code = parse_hex_or_decimal(command_name) as u16;
-            warnings.push(ErrorFormat {
-                source: ctx.source.to_string(),
-                err_msg: format!("unknown command name '{command_name}'. Using code 0x{code:04X}."),
-                span: ctx.span.clone(),
-                file: ctx.file.map(|p| p.to_path_buf()),
-            });
-            code
-        }
-    };
+    let (mut command_code, command_name) = lookup_command(charmap, command_name, ctx, warnings);
 
     // Set up iterator for parameters and get parameter count
     let mut param_iter = parts.iter();
     let mut param_len = parts.len();
 
-    // Assume this is the special byte for now
-    if param_len > 0 {
-        let special_byte_str = parts[0];
-        let special_byte = parse_hex_or_decimal(special_byte_str);
-
-        if command_name.starts_with("STRVAR_") {
-            command_code |= special_byte as u16;
-            param_iter.next(); // consume special byte
-            param_len -= 1;
-        }
+    // Only commands with a special byte in the charmap take it as their first parameter
+    if param_len > 0 && charmap.has_special_byte(command_name) {
+        let special_byte =
+            parse_command_number(parts[0], "special byte", command_str, ctx, warnings)?;
+        command_code |= special_byte;
+        param_iter.next(); // consume special byte
+        param_len -= 1;
     }
 
     // Push command marker
-    command_codes.push(0xFFFE);
+    command_codes.push(charmap.control_codes.command_marker);
     command_codes.push(command_code);
 
     // Remaining parts are parameters
@@ -775,69 +1776,1235 @@ fn encode_command_msgenc(
 
     let mut debug_params = Vec::new();
 
-    for param_str in param_iter {
-        let param = parse_hex_or_decimal(param_str) as u16;
+    for (index, param_str) in param_iter.enumerate() {
+        let param = parse_command_number(
+            param_str,
+            &format!("parameter {index}"),
+            command_str,
+            ctx,
+            warnings,
+        )?;
         command_codes.push(param);
         debug_params.push(format!("0x{:04X}", param));
     }
 
-    command_codes
+    Ok(command_codes)
 }
 
+/// Encode a trainer name as 9-bit codes packed into u16s, ended by the 9-bit code 0x1FF.
+///
+/// At the end of a message the game reads the missing bits of the termination code from the
+/// message terminator, so like msgenc only the unused bits of the last u16 are filled with ones.
+/// Anywhere else, or when the terminator doesn't supply those bits, the whole termination code
+/// is written so decoding never runs into the codes that follow the name.
 fn encode_trainer_name(
     charmap: &charmap::Charmap,
     name_str: &str,
+    at_end_of_message: bool,
     ctx: &mut DiagnosticContext<'_>,
     warnings: &mut Vec<ErrorFormat>,
-) -> Vec<u16> {
+) -> Result<Vec<u16>, ChatotError> {
     let mut name_codes = Vec::new();
 
-    name_codes.push(0xF100); // Trainer name command code
-
-    let mut bit = 0;
-    let mut current_u16 = 0u16;
-
-    // Pack 9-bit character codes into u16s. MSB is always 0 except for terminator
-    for ch in name_str.chars() {
-        let code = if charmap.encode_map.contains_key(&ch.to_string()) {
-            *charmap.encode_map.get(&ch.to_string()).unwrap()
+    name_codes.push(charmap.control_codes.trainer_name); // Trainer name command code
+
+    // The name is a stream of 9-bit character codes stored in the low 15 bits of each u16,
+    // filled from the least significant bit up. Bits not yet written out are kept in `pending`.
+    let mut pending: u32 = 0;
+    let mut pending_bits = 0;
+
+    let mut rest = name_str;
+    while let Some(ch) = rest.chars().next() {
+        // Codes the charmap doesn't know are decoded as \xXXXX, like outside of names
+        let escaped = rest
+            .strip_prefix("\\x")
+            .and_then(|hex| hex.get(..4))
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok());
+        let (len, code) = if let Some(code) = escaped {
+            if code > 0x1FF {
+                let code = ctx.unencodable(
+                    warnings,
+                    format!("code 0x{code:04X} in trainer name doesn't fit in 9 bits"),
+                )?;
+                (6, code)
+            } else {
+                (6, Some(code))
+            }
+        } else if let Some((len, code)) = charmap.longest_match(rest) {
+            (len, Some(code))
+        } else if let Some(code) = charmap.encode_map.get(&ch.to_string()) {
+            (ch.len_utf8(), Some(*code))
         } else {
-            warnings.push(ErrorFormat {
-                source: ctx.source.to_string(),
-                err_msg: format!("unknown character '{ch}' in trainer name. Using null code."),
-                span: ctx.span.clone(),
-                file: ctx.file.map(|p| p.to_path_buf()),
-            });
-            0
+            let code = ctx.unencodable(
+                warnings,
+                format!("unknown character '{ch}' in trainer name"),
+            )?;
+            (ch.len_utf8(), code)
+        };
+        rest = &rest[len..];
+        let Some(code) = code else {
+            continue;
         };
 
-        current_u16 |= (code & 0x1FF) << bit;
-        bit += 9;
+        pending |= ((code & 0x1FF) as u32) << pending_bits;
+        pending_bits += 9;
 
-        // If we've filled a u16, push it and start a new one
-        if bit >= 15 {
-            name_codes.push(current_u16 & 0x7FFF);
-            bit -= 15;
-            current_u16 = (code >> (9 - bit)) & 0x1FF;
+        // Emit every completely filled u16
+        while pending_bits >= 15 {
+            name_codes.push((pending & 0x7FFF) as u16);
+            pending >>= 15;
+            pending_bits -= 15;
         }
     }
 
-    // If there are remaining bits, push the last u16
-    if bit > 0 {
-        // Shift the 9-bit termination code (0x1FF) into the remaining bits and emit the final u16
-        current_u16 |= 0xFFFF << bit;
-        name_codes.push(current_u16 & 0x7FFF);
+    let terminator_completes_name = charmap.control_codes.terminator & 0x1FF == 0x1FF;
+    if !(at_end_of_message && terminator_completes_name) {
+        pending |= 0x1FF << pending_bits;
+        pending_bits += 9;
+        while pending_bits >= 15 {
+            name_codes.push((pending & 0x7FFF) as u16);
+            pending >>= 15;
+            pending_bits -= 15;
+        }
+    }
+
+    // Fill the unused bits of the last u16 with ones, which the decoder reads as the start of the
+    // 9-bit termination code 0x1FF
+    if pending_bits > 0 {
+        name_codes.push(((pending | (0xFFFF << pending_bits)) & 0x7FFF) as u16);
     }
 
-    name_codes
+    Ok(name_codes)
+}
+
+/// Parse a number that is part of a command, called `name` in warnings. Numbers that can't be
+/// read fail encoding under the error policy, otherwise they're encoded as 0 with a warning.
+fn parse_command_number(
+    number_str: &str,
+    name: &str,
+    command_str: &str,
+    ctx: &DiagnosticContext<'_>,
+    warnings: &mut Vec<ErrorFormat>,
+) -> Result<u16, ChatotError> {
+    let reason = match parse_number(number_str) {
+        Ok(number) => return Ok(number),
+        Err(reason) => reason,
+    };
+    let err_msg = format!("{name} '{number_str}' of command '{command_str}': {reason}");
+
+    if ctx.on_unknown == OnUnknown::Error {
+        return Err(ChatotError::Unencodable(
+            ctx.error_format(WarningKind::Number, err_msg),
+        ));
+    }
+    warnings.push(ctx.error_format(WarningKind::Number, format!("{err_msg}. Using 0x0000.")));
+    Ok(0)
 }
 
-fn parse_hex_or_decimal(number_str: &str) -> u32 {
-    let number = if number_str.starts_with("0x") {
-        u32::from_str_radix(&number_str[2..], 16).unwrap_or(0)
+/// Parse a 16-bit number written as decimal, `0x` hex or `0b` binary, with optional `_`
+/// separators between digits like `0x1F_FF`. Negative decimals are stored as two's complement,
+/// so `-1` is `0xFFFF`.
+pub(crate) fn parse_number(number_str: &str) -> Result<u16, ParseNumberError> {
+    let (negative, unsigned) = match number_str.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, number_str),
+    };
+    let (radix, digits) = if let Some(hex) = unsigned.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(binary) = unsigned.strip_prefix("0b") {
+        (2, binary)
     } else {
-        number_str.parse::<u32>().unwrap_or(0)
+        (10, unsigned)
     };
-    number
+    // Only decimals can be negative
+    if negative && radix != 10 {
+        return Err(ParseNumberError::InvalidDigit);
+    }
+    // Separators only go between two digits, in every base
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err(ParseNumberError::InvalidDigit);
+    }
+
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return Err(ParseNumberError::Empty);
+    }
+    let mut value: u32 = 0;
+    for ch in digits.chars() {
+        let digit = ch.to_digit(radix).ok_or(ParseNumberError::InvalidDigit)?;
+        value = value * radix + digit;
+        if value > u16::MAX as u32 + 1 {
+            return Err(ParseNumberError::Overflow);
+        }
+    }
+
+    match (negative, value) {
+        (false, value) if value <= u16::MAX as u32 => Ok(value as u16),
+        (true, value) if value <= 1 << 15 => Ok((value as u16).wrapping_neg()),
+        _ => Err(ParseNumberError::Overflow),
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plaintext_charmap() -> charmap::Charmap {
+        charmap::Charmap {
+            crypto: CryptoParams::PLAINTEXT,
+            ..charmap::get_default_charmap().clone()
+        }
+    }
+
+    fn strings(messages: &[&str]) -> Vec<String> {
+        messages.iter().map(|message| message.to_string()).collect()
+    }
+
+    #[test]
+    fn dedup_shares_identical_plaintext_messages() {
+        let charmap = plaintext_charmap();
+        let messages = strings(&["Hi", "Ok", "Hi"]);
+        let dedup = EncodeOptions {
+            dedup: true,
+            ..Default::default()
+        };
+
+        let plain =
+            encode_messages(&charmap, 0, &messages, EncodeOptions::default(), None).unwrap();
+        let shared = encode_messages(&charmap, 0, &messages, dedup, None).unwrap();
+        // "Hi" and its terminator are 3 codes written once instead of twice
+        assert_eq!(shared.len() + 6, plain.len());
+
+        let decoded =
+            crate::decode::decode_archive(&charmap, &shared, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn dedup_finds_nothing_to_share_in_encrypted_messages() {
+        let charmap = charmap::get_default_charmap();
+        let messages = strings(&["Hi", "Ok", "Hi"]);
+        let dedup = EncodeOptions {
+            dedup: true,
+            ..Default::default()
+        };
+
+        let plain = encode_messages(charmap, 0, &messages, EncodeOptions::default(), None).unwrap();
+        let shared = encode_messages(charmap, 0, &messages, dedup, None).unwrap();
+        assert_eq!(shared, plain);
+    }
+
+    /// Small charmap with a few letters, a space, a line break and one command, enough to build
+    /// every kind of message without depending on the Gen IV map.
+    const FIXTURE_CHARMAP: &str = r#"{
+        "char_map": {
+            "0145": { "char": "a" },
+            "0146": { "char": "b" },
+            "0147": { "char": "c" },
+            "0148": { "char": "d" },
+            "0149": { "char": "e" },
+            "01DE": { "char": " " },
+            "E000": { "char": "\\n" }
+        },
+        "command_map": {
+            "0200": "YESNO"
+        }
+    }"#;
+
+    /// Deterministic xorshift generator, so failing cases can be reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn random_message(rng: &mut Rng) -> String {
+        const CHARS: [&str; 7] = ["a", "b", "c", "d", "e", " ", "\\n"];
+        let mut message = String::new();
+        for _ in 0..rng.below(8) {
+            match rng.below(10) {
+                0 => message.push_str(&format!("{{YESNO, 0, {}}}", rng.below(100))),
+                1 => {
+                    let name: String = (0..1 + rng.below(7))
+                        .map(|_| CHARS[rng.below(5) as usize])
+                        .collect();
+                    message.push_str(&format!("{{TRAINER_NAME:{name}}}"));
+                }
+                _ => message.push_str(CHARS[rng.below(CHARS.len() as u64) as usize]),
+            }
+        }
+        message
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for case in 0..500 {
+            let key = rng.below(0x10000) as u16;
+            let messages: Vec<String> = (0..rng.below(6))
+                .map(|_| random_message(&mut rng))
+                .collect();
+
+            let archive =
+                encode_messages(&charmap, key, &messages, EncodeOptions::default(), None).unwrap();
+            let decoded =
+                crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+            assert_eq!(decoded.key, key, "case {case}");
+            assert_eq!(decoded.messages, messages, "case {case}");
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip_of_empty_messages_and_trainer_names() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let messages = strings(&[
+            "",
+            "{TRAINER_NAME:abc}",
+            "",
+            "a{TRAINER_NAME:abcde}b",
+            "{TRAINER_NAME:a}",
+        ]);
+        let archive =
+            encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None).unwrap();
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn encode_decode_round_trip_in_both_byte_orders() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let messages = strings(&["abc", "", "d{YESNO, 0, 1}e"]);
+        let mut archives = Vec::new();
+        for endian in [Endian::Little, Endian::Big] {
+            let opts = EncodeOptions {
+                endian,
+                ..Default::default()
+            };
+            let archive = encode_messages(&charmap, 0x1234, &messages, opts, None).unwrap();
+            let decoded = crate::decode::decode_archive(&charmap, &archive, false, endian).unwrap();
+            assert_eq!(decoded.key, 0x1234, "{endian:?}");
+            assert_eq!(decoded.messages, messages, "{endian:?}");
+            archives.push(archive);
+        }
+        // The message count comes first in the header
+        assert_eq!(archives[0][..2], [3, 0]);
+        assert_eq!(archives[1][..2], [0, 3]);
+    }
+
+    #[test]
+    fn charmap_can_override_the_terminator() {
+        let charmap = charmap::decode_charmap(
+            r#"{
+                "char_map": { "0145": { "char": "a" }, "0146": { "char": "b" } },
+                "command_map": {},
+                "control_codes": { "terminator": "0000" },
+                "crypto": { "table_seed": 0, "msg_mul": 0, "msg_add": 0 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(charmap.control_codes.terminator, 0x0000);
+
+        let messages = strings(&["ab"]);
+        let archive =
+            encode_messages(&charmap, 0, &messages, EncodeOptions::default(), None).unwrap();
+        assert_eq!(archive[archive.len() - 6..], [0x45, 0x01, 0x46, 0x01, 0, 0]);
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn plain_text_starting_with_a_bom_keeps_its_key_and_first_message() {
+        let text = "\u{FEFF}// Key: 0x1\nHello";
+        let layout = TextLayout {
+            keep_crlf: false,
+            separator: None,
+            markers: false,
+        };
+        let parsed = parse_text_messages(text, layout).unwrap();
+        assert_eq!(parsed.key, 0x1);
+        assert_eq!(parsed.messages, strings(&["Hello"]));
+
+        let charmap = charmap::get_default_charmap();
+        let archive = encode_text(charmap, text, layout, EncodeOptions::default(), None)
+            .unwrap()
+            .data;
+        let decoded =
+            crate::decode::decode_archive(charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.key, 0x1);
+        assert_eq!(decoded.messages, strings(&["Hello"]));
+    }
+
+    #[test]
+    fn text_directory_is_encoded_in_name_order() {
+        let dir = archive::test_dir("encode-name-order");
+        let (text_dir, archive_dir) = (dir.join("text"), dir.join("archives"));
+        std::fs::create_dir(&text_dir).unwrap();
+        std::fs::create_dir(&archive_dir).unwrap();
+        // Created out of order, {index} follows the sorted names
+        for name in ["c", "a", "b"] {
+            std::fs::write(text_dir.join(format!("{name}.txt")), name).unwrap();
+        }
+        let source = crate::TextSource {
+            txt: None,
+            text_dir: Some(text_dir),
+            stdin: false,
+        };
+        let destination = crate::BinarySource {
+            archive: None,
+            archive_dir: Some(archive_dir.clone()),
+            container: None,
+        };
+        let settings = crate::Settings {
+            name_pattern: Some("{index}.bin".to_string()),
+            ..Default::default()
+        };
+        let charmap = charmap::get_default_charmap();
+        encode_texts(charmap, &source, &destination, &settings).unwrap();
+
+        for (index, name) in ["a", "b", "c"].iter().enumerate() {
+            let data = std::fs::read(archive_dir.join(format!("{index}.bin"))).unwrap();
+            let decoded =
+                crate::decode::decode_archive(charmap, &data, false, Endian::Little).unwrap();
+            assert_eq!(decoded.messages, strings(&[name]));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_batches_keep_their_own_warnings() {
+        let dir = archive::test_dir("encode-warnings-json");
+        let charmap = charmap::get_default_charmap();
+        std::thread::scope(|scope| {
+            for name in ["first", "second"] {
+                let dir = &dir;
+                scope.spawn(move || {
+                    let (text_dir, archive_dir) = (dir.join(name), dir.join(format!("{name}-bin")));
+                    std::fs::create_dir(&text_dir).unwrap();
+                    std::fs::create_dir(&archive_dir).unwrap();
+                    for file in 0..20 {
+                        std::fs::write(text_dir.join(format!("{file:02}.txt")), "a{FOO}").unwrap();
+                    }
+                    let source = crate::TextSource {
+                        txt: None,
+                        text_dir: Some(text_dir.clone()),
+                        stdin: false,
+                    };
+                    let destination = crate::BinarySource {
+                        archive: None,
+                        archive_dir: Some(archive_dir),
+                        container: None,
+                    };
+                    let settings = crate::Settings {
+                        warnings_file: Some(dir.join(format!("{name}.json"))),
+                        ..Default::default()
+                    };
+                    encode_texts(charmap, &source, &destination, &settings).unwrap();
+
+                    let records: Vec<serde_json::Value> = serde_json::from_slice(
+                        &std::fs::read(dir.join(format!("{name}.json"))).unwrap(),
+                    )
+                    .unwrap();
+                    let files: Vec<_> = records
+                        .iter()
+                        .map(|record| PathBuf::from(record["file"].as_str().unwrap()))
+                        .collect();
+                    let expected: Vec<_> = (0..20)
+                        .map(|file| text_dir.join(format!("{file:02}.txt")))
+                        .collect();
+                    assert_eq!(files, expected);
+                });
+            }
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn longest_sequence_in_the_charmap_wins() {
+        let charmap = charmap::Charmap {
+            crypto: CryptoParams::PLAINTEXT,
+            ..charmap::decode_charmap(
+                r#"{
+                    "char_map": {
+                        "0145": { "char": "a" },
+                        "0146": { "char": "b" },
+                        "0150": { "char": "ab" }
+                    },
+                    "command_map": {}
+                }"#,
+            )
+            .unwrap()
+        };
+        let messages = strings(&["aab"]);
+        let archive =
+            encode_messages(&charmap, 0, &messages, EncodeOptions::default(), None).unwrap();
+        assert_eq!(
+            archive[archive.len() - 6..],
+            [0x45, 0x01, 0x50, 0x01, 0xFF, 0xFF]
+        );
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    /// Trainer name packed bit by bit: the 9-bit codes and the 0x1FF termination code from the
+    /// least significant bit up, 15 bits per u16, with the unused bits of the last one set.
+    fn reference_trainer_name(codes: &[u16]) -> Vec<u16> {
+        let mut bits: Vec<bool> = codes
+            .iter()
+            .chain([&0x1FF])
+            .flat_map(|code| (0..9).map(move |bit| (code >> bit) & 1 == 1))
+            .collect();
+        while !bits.len().is_multiple_of(15) {
+            bits.push(true);
+        }
+        let mut packed = vec![0xF100];
+        packed.extend(bits.chunks(15).map(|chunk| {
+            chunk
+                .iter()
+                .rev()
+                .fold(0, |acc, &bit| (acc << 1) | bit as u16)
+        }));
+        packed
+    }
+
+    #[test]
+    fn trainer_names_of_every_length_round_trip() {
+        const CHARS: [(char, u16); 5] = [
+            ('a', 0x145),
+            ('b', 0x146),
+            ('c', 0x147),
+            ('d', 0x148),
+            ('e', 0x149),
+        ];
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        for len in 1..=20 {
+            let name: String = (0..len).map(|i| CHARS[i % CHARS.len()].0).collect();
+            let codes: Vec<u16> = (0..len).map(|i| CHARS[i % CHARS.len()].1).collect();
+            let mut ctx = DiagnosticContext {
+                source: &name,
+                file: None,
+                message_index: None,
+                span: 0..0,
+                on_unknown: OnUnknown::Error,
+            };
+            let mut warnings = Vec::new();
+            let packed = encode_trainer_name(&charmap, &name, false, &mut ctx, &mut warnings);
+            assert_eq!(
+                packed.unwrap(),
+                reference_trainer_name(&codes),
+                "length {len}"
+            );
+
+            let messages = vec![
+                format!("{{TRAINER_NAME:{name}}}"),
+                format!("{{TRAINER_NAME:{name}}}a"),
+            ];
+            let archive =
+                encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None)
+                    .unwrap();
+            let decoded =
+                crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+            assert_eq!(decoded.messages, messages, "length {len}");
+        }
+    }
+
+    #[test]
+    fn trainer_name_ending_on_a_u16_boundary_is_still_terminated() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let mut ctx = DiagnosticContext {
+            source: "",
+            file: None,
+            message_index: None,
+            span: 0..0,
+            on_unknown: OnUnknown::Error,
+        };
+        let mut warnings = Vec::new();
+        // Four characters and the termination code are 45 bits, exactly three u16s
+        let packed = encode_trainer_name(&charmap, "abcd", false, &mut ctx, &mut warnings).unwrap();
+        assert_eq!(packed.len(), 4);
+        assert_eq!(packed[3] >> 6, 0x1FF);
+        // Five characters are 45 bits on their own, the message terminator ends the name
+        let packed = encode_trainer_name(&charmap, "abcde", true, &mut ctx, &mut warnings).unwrap();
+        assert_eq!(packed.len(), 4);
+
+        let messages = strings(&[
+            "{TRAINER_NAME:abcd}b",
+            "a{TRAINER_NAME:abcde}",
+            "{TRAINER_NAME:abcd}",
+        ]);
+        let archive =
+            encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None).unwrap();
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn charmap_table_seed_round_trips() {
+        let default_charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let charmap = charmap::Charmap {
+            crypto: CryptoParams {
+                table_seed: 1234,
+                ..CryptoParams::default()
+            },
+            ..default_charmap.clone()
+        };
+        let messages = strings(&["abc", "de"]);
+        let archive =
+            encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None).unwrap();
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+
+        // Only the message table is encrypted with the seed
+        let default_archive = encode_messages(
+            &default_charmap,
+            0x1234,
+            &messages,
+            EncodeOptions::default(),
+            None,
+        )
+        .unwrap();
+        let table = 4..4 + 2 * archive::TABLE_ENTRY_SIZE;
+        assert_ne!(archive[table.clone()], default_archive[table.clone()]);
+        assert_eq!(archive[table.end..], default_archive[table.end..]);
+    }
+
+    #[test]
+    fn round_trip_with_arbitrary_crypto_params() {
+        let fixture = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        for case in 0..200 {
+            let charmap = charmap::Charmap {
+                crypto: CryptoParams {
+                    table_seed: rng.next() as u32,
+                    msg_mul: rng.next() as u32,
+                    msg_add: rng.next() as u16,
+                },
+                ..fixture.clone()
+            };
+            let key = rng.below(0x10000) as u16;
+            let messages: Vec<String> = (0..1 + rng.below(5))
+                .map(|_| random_message(&mut rng))
+                .collect();
+            let archive =
+                encode_messages(&charmap, key, &messages, EncodeOptions::default(), None).unwrap();
+            let decoded =
+                crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+            assert_eq!(
+                decoded.messages, messages,
+                "case {case}: {:?}",
+                charmap.crypto
+            );
+        }
+    }
+
+    #[test]
+    fn plaintext_archive_holds_the_codes_as_they_are() {
+        let charmap = plaintext_charmap();
+        let messages = strings(&["Hi", ""]);
+        let archive =
+            encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None).unwrap();
+        let h = charmap.encode_map["H"].to_le_bytes();
+        let i = charmap.encode_map["i"].to_le_bytes();
+        #[rustfmt::skip]
+        assert_eq!(archive, [
+            2, 0, 0x34, 0x12,
+            20, 0, 0, 0, 3, 0, 0, 0,
+            26, 0, 0, 0, 1, 0, 0, 0,
+            h[0], h[1], i[0], i[1], 0xFF, 0xFF,
+            0xFF, 0xFF,
+        ]);
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.key, 0x1234);
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn force_rewrites_archives_newer_than_their_text() {
+        let dir = archive::test_dir("encode-force");
+        let (text_path, archive_path) = (dir.join("a.txt"), dir.join("a.bin"));
+        std::fs::write(&text_path, "// Key: 0x1\nHello").unwrap();
+        std::fs::write(&archive_path, "old").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&archive_path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let source = crate::TextSource {
+            txt: Some(vec![text_path]),
+            text_dir: None,
+            stdin: false,
+        };
+        let destination = crate::BinarySource {
+            archive: Some(vec![archive_path.clone()]),
+            archive_dir: None,
+            container: None,
+        };
+        let charmap = charmap::get_default_charmap();
+        let newer = crate::Settings {
+            newer_only: true,
+            ..Default::default()
+        };
+        encode_texts(charmap, &source, &destination, &newer).unwrap();
+        assert_eq!(std::fs::read(&archive_path).unwrap(), b"old");
+
+        let forced = crate::Settings {
+            force: true,
+            ..newer
+        };
+        encode_texts(charmap, &source, &destination, &forced).unwrap();
+        let data = std::fs::read(&archive_path).unwrap();
+        let decoded = crate::decode::decode_archive(charmap, &data, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, strings(&["Hello"]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Codes of a single message and the warnings encoding it gave, under the error policy.
+    fn encode_codes(
+        charmap: &charmap::Charmap,
+        message: &str,
+        msgenc_format: bool,
+    ) -> Result<(Vec<u16>, Vec<ErrorFormat>), ChatotError> {
+        let mut warnings = Vec::new();
+        let mut ctx = DiagnosticContext {
+            source: message,
+            file: None,
+            message_index: None,
+            span: 0..0,
+            on_unknown: OnUnknown::Error,
+        };
+        let codes =
+            encode_string_to_message(charmap, message, msgenc_format, &mut warnings, &mut ctx)?;
+        Ok((codes, warnings))
+    }
+
+    #[test]
+    fn combining_accent_sequence_encodes_to_its_own_code() {
+        let charmap = charmap::Charmap {
+            crypto: CryptoParams::PLAINTEXT,
+            ..charmap::decode_charmap(
+                r#"{
+                    "char_map": {
+                        "0149": { "char": "e" },
+                        "0150": { "char": "e\u0301" }
+                    },
+                    "command_map": {}
+                }"#,
+            )
+            .unwrap()
+        };
+        let terminator = charmap.control_codes.terminator;
+
+        let (codes, warnings) = encode_codes(&charmap, "e\u{0301}e", false).unwrap();
+        assert_eq!(codes, [0x150, 0x149, terminator]);
+        assert!(warnings.is_empty());
+
+        let (codes, warnings) = encode_codes(&charmap, "{TRAINER_NAME:e\u{0301}e}", false).unwrap();
+        let mut expected = reference_trainer_name(&[0x150, 0x149]);
+        expected.push(terminator);
+        assert_eq!(codes, expected);
+        assert!(warnings.is_empty());
+
+        let messages = strings(&["e\u{0301}e", "{TRAINER_NAME:e\u{0301}e}"]);
+        let archive =
+            encode_messages(&charmap, 0, &messages, EncodeOptions::default(), None).unwrap();
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn lowercase_command_name_resolves_with_a_warning() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        for (exact, lowercase, msgenc_format) in [
+            ("{YESNO, 0, 1}", "{yesno, 0, 1}", false),
+            ("{YESNO 1}", "{yesno 1}", true),
+        ] {
+            let (expected, warnings) = encode_codes(&charmap, exact, msgenc_format).unwrap();
+            assert!(warnings.is_empty());
+            let (codes, warnings) = encode_codes(&charmap, lowercase, msgenc_format).unwrap();
+            assert_eq!(codes, expected, "{lowercase}");
+            assert_eq!(warnings.len(), 1, "{lowercase}");
+            assert_eq!(warnings[0].kind, WarningKind::Command);
+            assert!(warnings[0].err_msg.contains("matched 'YESNO'"));
+        }
+    }
+
+    #[test]
+    fn command_parameters_that_cant_be_read_are_reported() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        for (message, reason) in [
+            (
+                "{YESNO, 0, 1, 70000}",
+                "parameter 1 '70000' of command 'YESNO, 0, 1, 70000': does not fit in 16 bits",
+            ),
+            (
+                "{YESNO, 0, abc}",
+                "parameter 0 'abc' of command 'YESNO, 0, abc': not a number",
+            ),
+        ] {
+            match encode_codes(&charmap, message, false) {
+                Err(ChatotError::Unencodable(error)) => {
+                    assert_eq!(error.kind, WarningKind::Number);
+                    assert_eq!(error.err_msg, reason);
+                }
+                Err(other) => panic!("expected an unencodable error, got {other}"),
+                Ok(_) => panic!("{message} was encoded"),
+            }
+
+            let warnings = message_warnings(&charmap, message, false, None);
+            assert_eq!(warnings.len(), 1, "{message}");
+            assert_eq!(warnings[0].err_msg, format!("{reason}. Using 0x0000."));
+        }
+    }
+
+    #[test]
+    fn parse_number_reads_every_form() {
+        for (number_str, expected) in [
+            ("0", 0),
+            ("65535", 0xFFFF),
+            ("0x1F", 0x1F),
+            ("0xffff", 0xFFFF),
+            ("0b101", 5),
+            ("-1", 0xFFFF),
+            ("-32768", 0x8000),
+            ("1_000", 1000),
+            ("0x1F_FF", 0x1FFF),
+            ("0b1_0000_0000", 256),
+        ] {
+            assert_eq!(parse_number(number_str), Ok(expected), "{number_str}");
+        }
+    }
+
+    #[test]
+    fn parse_number_rejects_garbage() {
+        use ParseNumberError::*;
+        for (number_str, error) in [
+            ("", Empty),
+            ("0x", Empty),
+            ("-", Empty),
+            ("abc", InvalidDigit),
+            ("12a", InvalidDigit),
+            ("0x1G", InvalidDigit),
+            ("0b102", InvalidDigit),
+            ("-0x1", InvalidDigit),
+            ("+1", InvalidDigit),
+            (" 1", InvalidDigit),
+            ("_1", InvalidDigit),
+            ("12_", InvalidDigit),
+            ("1__2", InvalidDigit),
+            ("0x_FF", InvalidDigit),
+            ("0xFF_", InvalidDigit),
+            ("0b_1", InvalidDigit),
+            ("_", InvalidDigit),
+            ("65536", Overflow),
+            ("0x10000", Overflow),
+            ("-32769", Overflow),
+            ("99999999999999999999", Overflow),
+        ] {
+            assert_eq!(parse_number(number_str), Err(error), "{number_str}");
+        }
+    }
+
+    #[test]
+    fn json_lines_are_joined_with_a_command() {
+        let json = r#"{
+            "key": 4660,
+            "messages": [
+                { "id": "msg_0", "en_US": ["ab", "c", "de"] },
+                { "id": "msg_1", "en_US": "e" }
+            ]
+        }"#;
+        let base_lang = strings(&["en_US"]);
+        let mut json_opts = JsonMessageOptions {
+            lang: "en_US",
+            base_lang: &base_lang,
+            ordered_by_id: false,
+            multi_join: "",
+        };
+        let archives = parse_json_archives(json, &json_opts).unwrap();
+        assert_eq!(archives[0].messages, strings(&["abcde", "e"]));
+
+        json_opts.multi_join = "{YESNO, 0, 0}";
+        let expected = strings(&["ab{YESNO, 0, 0}c{YESNO, 0, 0}de", "e"]);
+        let archives = parse_json_archives(json, &json_opts).unwrap();
+        assert_eq!(archives[0].messages, expected);
+
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let encoded =
+            encode_json(&charmap, json, &json_opts, EncodeOptions::default(), None).unwrap();
+        let decoded =
+            crate::decode::decode_archive(&charmap, &encoded[0].data, false, Endian::Little)
+                .unwrap();
+        assert_eq!(decoded.messages, expected);
+    }
+
+    #[test]
+    fn aligned_messages_round_trip() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let messages = strings(&["a", "ab", "", "abc", "{YESNO, 0, 1}"]);
+        let opts = EncodeOptions {
+            align: Some(Align::Four),
+            ..Default::default()
+        };
+        let archive = encode_messages(&charmap, 0x1234, &messages, opts, None).unwrap();
+        let (_, table) =
+            crate::decode::read_message_table(&archive, Endian::Little, charmap.crypto.table_seed)
+                .unwrap();
+        assert!(table.iter().all(|entry| entry.offset % 4 == 0));
+        assert_eq!(archive.len() % 4, 0);
+
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+    }
+
+    #[test]
+    fn index_directive_encrypts_a_message_as_another_one() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let text = "// Key: 0x1234\nab\n// Index: 2\nab\nab\n";
+        let layout = TextLayout {
+            keep_crlf: false,
+            separator: None,
+            markers: false,
+        };
+        let archive = encode_text(&charmap, text, layout, EncodeOptions::default(), None)
+            .unwrap()
+            .data;
+
+        // Each "ab" is three codes after the header and the table of three entries
+        let data = |index: usize| &archive[28 + index * 6..34 + index * 6];
+        assert_ne!(data(1), data(0));
+        assert_eq!(data(1), data(2));
+
+        // The table entry is encrypted with the overridden index as well, counting from 1
+        let entry = |index: usize| {
+            let at = 4 + index * archive::TABLE_ENTRY_SIZE;
+            u32::from_le_bytes(archive[at..at + 4].try_into().unwrap())
+        };
+        let key = |index: u32| crypto::table_key(0x1234, index, charmap.crypto.table_seed);
+        assert_eq!(entry(0) ^ key(1), 28);
+        assert_eq!(entry(1) ^ key(3), 34);
+        assert_eq!(entry(2) ^ key(3), 40);
+    }
+
+    #[test]
+    fn message_count_fits_the_16_bit_header() {
+        let charmap = plaintext_charmap();
+        let messages = vec![String::new(); u16::MAX as usize];
+        let archive =
+            encode_messages(&charmap, 0, &messages, EncodeOptions::default(), None).unwrap();
+        assert_eq!(archive[..2], [0xFF, 0xFF]);
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages.len(), u16::MAX as usize);
+
+        let messages = vec![String::new(); u16::MAX as usize + 1];
+        match encode_messages(&charmap, 0, &messages, EncodeOptions::default(), None) {
+            Err(ChatotError::TooManyMessages { count }) => assert_eq!(count, 65536),
+            Err(other) => panic!("expected too many messages, got {other}"),
+            Ok(_) => panic!("65536 messages were encoded"),
+        }
+    }
+
+    #[test]
+    fn json_messages_fall_back_along_the_base_lang_chain() {
+        let json = r#"{
+            "key": 0,
+            "messages": [
+                { "id": "msg_0", "fr": "un", "fr_FR": "deux", "en_US": "trois" },
+                { "id": "msg_1", "fr_FR": "deux", "en_US": "trois" },
+                { "id": "msg_2", "en_US": "trois" },
+                { "id": "msg_3", "de": "vier" }
+            ]
+        }"#;
+        let base_lang = strings(&["fr_FR", "en_US"]);
+        let json_opts = JsonMessageOptions {
+            lang: "fr",
+            base_lang: &base_lang,
+            ordered_by_id: false,
+            multi_join: "",
+        };
+        match parse_json_archives(json, &json_opts) {
+            Err(ChatotError::LanguageNotFound {
+                lang,
+                fallbacks,
+                id,
+            }) => {
+                assert_eq!(lang, "fr");
+                assert_eq!(fallbacks, base_lang);
+                assert_eq!(id, "msg_3");
+            }
+            Err(other) => panic!("expected a missing language, got {other}"),
+            Ok(_) => panic!("msg_3 has no language of the chain"),
+        }
+
+        let json = json.replace(
+            r#"{ "id": "msg_3", "de": "vier" }"#,
+            r#"{ "id": "msg_3", "fr": "quatre" }"#,
+        );
+        let archives = parse_json_archives(&json, &json_opts).unwrap();
+        assert_eq!(
+            archives[0].messages,
+            strings(&["un", "deux", "trois", "quatre"])
+        );
+    }
+
+    #[test]
+    fn key_line_comes_once_before_the_messages() {
+        let layout = TextLayout {
+            keep_crlf: false,
+            separator: None,
+            markers: false,
+        };
+        let parsed = parse_text_messages("// Key: 0x1234\nab\n", layout).unwrap();
+        assert_eq!(parsed.key, 0x1234);
+        assert_eq!(parsed.messages, strings(&["ab"]));
+
+        // Comments may come first, and comments that only look like a key line are skipped
+        let parsed = parse_text_messages(
+            "// header\n// Key: 5\nab\n// Key 0x99\n//Key: 0x99\ncd\n",
+            layout,
+        )
+        .unwrap();
+        assert_eq!(parsed.key, 5);
+        assert_eq!(parsed.messages, strings(&["ab", "cd"]));
+
+        for (text, line, expected) in [
+            (
+                "ab\n// Key: 0x1234\n",
+                2,
+                "key line after the first message",
+            ),
+            ("// Key: 1\n// Key: 2\nab\n", 2, "more than one key line"),
+        ] {
+            match parse_text_messages(text, layout) {
+                Err(ChatotError::InvalidText { line: at, reason }) => {
+                    assert_eq!((at, reason), (line, expected), "{text:?}");
+                }
+                Err(other) => panic!("expected invalid text, got {other}"),
+                Ok(_) => panic!("{text:?} was parsed"),
+            }
+        }
+    }
+
+    #[test]
+    fn explicit_files_must_pair_up() {
+        let dir = archive::test_dir("encode-mismatch");
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(dir.join(name), "ab").unwrap();
+        }
+        let source = crate::TextSource {
+            txt: Some(vec![
+                dir.join("a.txt"),
+                dir.join("b.txt"),
+                dir.join("c.txt"),
+            ]),
+            text_dir: None,
+            stdin: false,
+        };
+        let destination = crate::BinarySource {
+            archive: Some(vec![dir.join("a.bin"), dir.join("b.bin")]),
+            archive_dir: None,
+            container: None,
+        };
+        let settings = crate::Settings::default();
+        let error = encode_texts(
+            charmap::get_default_charmap(),
+            &source,
+            &destination,
+            &settings,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "3 inputs but 2 outputs specified");
+        assert!(!dir.join("a.bin").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unmapped_codes_round_trip_as_escapes() {
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let messages = strings(&[
+            "a\\x0150b",
+            "\\xFFFD",
+            "{TRAINER_NAME:a\\x0150}",
+            "{TRAINER_NAME:\\x01FE}b",
+        ]);
+        let (codes, _) = encode_codes(&charmap, &messages[0], false).unwrap();
+        assert_eq!(codes, [0x0145, 0x0150, 0x0146, 0xFFFF]);
+
+        let archive =
+            encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None).unwrap();
+        let decoded =
+            crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+        assert_eq!(decoded.messages, messages);
+        let encoded = encode_messages(
+            &charmap,
+            0x1234,
+            &decoded.messages,
+            EncodeOptions::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(encoded, archive);
+    }
+
+    #[test]
+    fn special_byte_commands_agree_in_both_formats() {
+        let charmap = charmap::decode_charmap(
+            r#"{
+                "char_map": { "0145": { "char": "a" } },
+                "command_map": { "0100": "STRVAR_1", "0200": "YESNO" }
+            }"#,
+        )
+        .unwrap();
+        for (regular, msgenc, expected) in [
+            (
+                "{STRVAR_1, 3, 7}",
+                "{STRVAR_1 3, 7}",
+                [0xFFFE, 0x0103, 1, 7, 0xFFFF],
+            ),
+            ("{YESNO, 0, 7}", "{YESNO 7}", [0xFFFE, 0x0200, 1, 7, 0xFFFF]),
+        ] {
+            for (message, msgenc_format) in [(regular, false), (msgenc, true)] {
+                let (codes, warnings) = encode_codes(&charmap, message, msgenc_format).unwrap();
+                assert_eq!(codes, expected, "{message}");
+                assert!(warnings.is_empty(), "{message}");
+
+                let messages = strings(&[message]);
+                let opts = EncodeOptions {
+                    msgenc_format,
+                    ..Default::default()
+                };
+                let archive = encode_messages(&charmap, 0, &messages, opts, None).unwrap();
+                let decoded = crate::decode::decode_archive(
+                    &charmap,
+                    &archive,
+                    msgenc_format,
+                    Endian::Little,
+                )
+                .unwrap();
+                assert_eq!(decoded.messages, messages);
+            }
+        }
+
+        // A special byte on a command without one changes its code
+        let (codes, warnings) = encode_codes(&charmap, "{YESNO, 1, 7}", false).unwrap();
+        assert_eq!(codes[1], 0x0201);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    /// Timing baseline for encoding and decoding a synthetic 1000-message archive, run with
+    /// `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_encode_decode_1000_messages() {
+        const RUNS: u32 = 20;
+        let charmap = charmap::decode_charmap(FIXTURE_CHARMAP).unwrap();
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let messages: Vec<String> = (0..1000)
+            .map(|_| (0..8).map(|_| random_message(&mut rng)).collect())
+            .collect();
+
+        let start = std::time::Instant::now();
+        let mut archive = Vec::new();
+        for _ in 0..RUNS {
+            archive = encode_messages(&charmap, 0x1234, &messages, EncodeOptions::default(), None)
+                .unwrap();
+        }
+        let encode_time = start.elapsed() / RUNS;
+
+        let start = std::time::Instant::now();
+        for _ in 0..RUNS {
+            let decoded =
+                crate::decode::decode_archive(&charmap, &archive, false, Endian::Little).unwrap();
+            assert_eq!(decoded.messages.len(), messages.len());
+        }
+        let decode_time = start.elapsed() / RUNS;
+
+        eprintln!(
+            "1000 messages, {} bytes: encode {:.2?}, decode {:.2?} per run",
+            archive.len(),
+            encode_time,
+            decode_time
+        );
+    }
+
+    /// Timing of the full directory path, encoding text files into archives and decoding them
+    /// again for growing file counts, run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_directories_of_archives() {
+        let charmap = charmap::get_default_charmap();
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let settings = crate::Settings::default();
+
+        for files in [10, 100, 1000] {
+            let dir = archive::test_dir(&format!("encode-bench-{files}"));
+            let (text_dir, archive_dir, decoded_dir) =
+                (dir.join("text"), dir.join("archives"), dir.join("decoded"));
+            for path in [&text_dir, &archive_dir, &decoded_dir] {
+                std::fs::create_dir(path).unwrap();
+            }
+            for file in 0..files {
+                let messages: Vec<String> = (0..50)
+                    .map(|_| (0..4).map(|_| random_message(&mut rng)).collect())
+                    .collect();
+                std::fs::write(
+                    text_dir.join(format!("{file}.txt")),
+                    messages.join("\n") + "\n",
+                )
+                .unwrap();
+            }
+
+            let source = crate::TextSource {
+                txt: None,
+                text_dir: Some(text_dir.clone()),
+                stdin: false,
+            };
+            let archives = crate::BinarySource {
+                archive: None,
+                archive_dir: Some(archive_dir.clone()),
+                container: None,
+            };
+            let start = std::time::Instant::now();
+            encode_texts(charmap, &source, &archives, &settings).unwrap();
+            let encode_time = start.elapsed();
+
+            let destination = crate::TextDestination {
+                txt: None,
+                text_dir: Some(decoded_dir.clone()),
+                stdout: false,
+                combine: None,
+            };
+            let start = std::time::Instant::now();
+            crate::decode::decode_archives(charmap, &archives, &destination, &settings).unwrap();
+            let decode_time = start.elapsed();
+
+            assert_eq!(std::fs::read_dir(&decoded_dir).unwrap().count(), files);
+            eprintln!(
+                "{files} files of 50 messages: encode {:.2?}, decode {:.2?}",
+                encode_time, decode_time
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+
+use crate::encode::ErrorFormat;
+
+#[derive(Debug)]
+pub enum ChatotError {
+    /// The character map is not valid JSON or contains invalid entries
+    CharmapParse(String),
+    /// A message table entry points outside of the message data or overlaps another entry
+    InvalidArchive {
+        index: usize,
+        offset: u32,
+        length: u32,
+        /// Size of the whole archive in bytes
+        archive_len: u64,
+        reason: &'static str,
+    },
+    /// A JSON message has no text for the requested language or any of its fallbacks
+    LanguageNotFound {
+        lang: String,
+        fallbacks: Vec<String>,
+        id: String,
+    },
+    /// The key line of a text file doesn't hold a valid key
+    InvalidKey {
+        key: String,
+        reason: ParseNumberError,
+    },
+    /// An `// Index:` line of a text file doesn't hold a valid message index
+    InvalidIndex {
+        index: String,
+        reason: ParseNumberError,
+    },
+    /// A text file message doesn't have one `// Trainer name:` line per `{TRAINER_NAME}`
+    TrainerNameCount {
+        index: usize,
+        names: usize,
+        placeholders: usize,
+    },
+    /// A plain text file doesn't have the layout selected by the options
+    InvalidText {
+        line: usize,
+        reason: &'static str,
+    },
+    /// A CSV text file doesn't have the layout written by decoding
+    InvalidCsv {
+        line: usize,
+        reason: &'static str,
+    },
+    /// More messages than the 16-bit message count of an archive can hold
+    TooManyMessages {
+        count: usize,
+    },
+    /// Encoded message data past the offsets a message table can store
+    ArchiveTooLarge {
+        size: u64,
+    },
+    /// An archive ends before the message table its header announces
+    TruncatedTable {
+        count: u16,
+        archive_len: u64,
+    },
+    /// A container ends before the index its archive count announces
+    TruncatedContainer {
+        count: u32,
+        container_len: u64,
+    },
+    /// An offset in the index of a container doesn't point to where an archive can start
+    InvalidContainer {
+        index: usize,
+        offset: u32,
+        reason: &'static str,
+    },
+    /// Archives past the offsets the index of a container can store
+    ContainerTooLarge {
+        size: u64,
+    },
+    /// A JSON message id can't be used to place the message in the archive
+    InvalidMessageId {
+        id: String,
+        reason: &'static str,
+    },
+    /// Text that can't be encoded, reported when unknown text is treated as an error
+    Unencodable(ErrorFormat),
+    /// The JSON text input could not be parsed
+    Json(serde_json::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ChatotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatotError::CharmapParse(msg) => write!(f, "Failed to parse charmap: {msg}"),
+            ChatotError::InvalidArchive {
+                index,
+                offset,
+                length,
+                archive_len,
+                reason,
+            } => write!(
+                f,
+                "Invalid message entry {index}: {reason} \
+                 (offset=0x{offset:X}, length={length} codes, archive size=0x{archive_len:X})"
+            ),
+            ChatotError::LanguageNotFound {
+                lang,
+                fallbacks,
+                id,
+            } => {
+                write!(f, "Language '{lang}' not found in message {id}")?;
+                if !fallbacks.is_empty() {
+                    write!(f, " (fallbacks: {})", fallbacks.join(", "))?;
+                }
+                Ok(())
+            }
+            ChatotError::InvalidKey { key, reason } => write!(f, "Invalid key '{key}': {reason}"),
+            ChatotError::InvalidIndex { index, reason } => {
+                write!(f, "Invalid message index '{index}': {reason}")
+            }
+            ChatotError::TrainerNameCount {
+                index,
+                names,
+                placeholders,
+            } => write!(
+                f,
+                "Message {index} has {names} '// Trainer name:' line(s) but {placeholders} {{TRAINER_NAME}} placeholder(s)"
+            ),
+            ChatotError::InvalidText { line, reason } => {
+                write!(f, "Invalid text on line {line}: {reason}")
+            }
+            ChatotError::InvalidCsv { line, reason } => {
+                write!(f, "Invalid CSV on line {line}: {reason}")
+            }
+            ChatotError::TooManyMessages { count } => write!(
+                f,
+                "{count} messages don't fit in an archive, which holds at most {}",
+                u16::MAX
+            ),
+            ChatotError::TruncatedTable { count, archive_len } => write!(
+                f,
+                "Archive of {archive_len} bytes is too short for the message table of {count} messages"
+            ),
+            ChatotError::ArchiveTooLarge { size } => write!(
+                f,
+                "Archive of at least {size} bytes is too large, message offsets are limited to {} bytes",
+                u32::MAX
+            ),
+            ChatotError::TruncatedContainer {
+                count,
+                container_len,
+            } => write!(
+                f,
+                "Container of {container_len} bytes is too short for the index of {count} archives"
+            ),
+            ChatotError::InvalidContainer {
+                index,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Invalid container entry {index}: {reason} (offset=0x{offset:X})"
+            ),
+            ChatotError::ContainerTooLarge { size } => write!(
+                f,
+                "Container of at least {size} bytes is too large, archive offsets are limited to {} bytes",
+                u32::MAX
+            ),
+            ChatotError::InvalidMessageId { id, reason } => {
+                write!(f, "Invalid message id '{id}': {reason}")
+            }
+            ChatotError::Unencodable(e) => write!(f, "{e}"),
+            ChatotError::Json(e) => write!(f, "Invalid JSON: {e}"),
+            ChatotError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Why a number written in a text file, like a key or a command parameter, couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseNumberError {
+    /// There are no digits
+    Empty,
+    /// A character isn't a digit of the number's base
+    InvalidDigit,
+    /// The number doesn't fit in 16 bits, signed or unsigned
+    Overflow,
+}
+
+impl fmt::Display for ParseNumberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseNumberError::Empty => write!(f, "no digits"),
+            ParseNumberError::InvalidDigit => write!(f, "not a number"),
+            ParseNumberError::Overflow => write!(f, "does not fit in 16 bits"),
+        }
+    }
+}
+
+impl std::error::Error for ParseNumberError {}
+
+impl std::error::Error for ChatotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChatotError::Json(e) => Some(e),
+            ChatotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ChatotError {
+    fn from(e: std::io::Error) -> Self {
+        ChatotError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ChatotError {
+    fn from(e: serde_json::Error) -> Self {
+        ChatotError::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_archive_error_shows_entry_and_archive_size() {
+        let error = ChatotError::InvalidArchive {
+            index: 2,
+            offset: 0x20,
+            length: 4,
+            archive_len: 0x24,
+            reason: "message extends past the end of the archive",
+        };
+        assert_eq!(
+            error.to_string(),
+            "Invalid message entry 2: message extends past the end of the archive \
+             (offset=0x20, length=4 codes, archive size=0x24)"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use serde_derive::Serialize;
+
+use crate::{
+    archive::{self, Endian},
+    crypto::CryptoParams,
+    decode,
+};
+
+/// Where a message lies in an archive and the codes it starts and ends with after decryption.
+#[derive(Serialize)]
+struct MessageRow {
+    archive: String,
+    index: usize,
+    offset: u32,
+    length: u32,
+    bytes: u32,
+    first_code: Option<String>,
+    last_code: Option<String>,
+}
+
+/// Print the message count, key, size and message length range of each archive.
+/// Only the header and the message table are read, no messages are decoded.
+pub fn print_archive_info(
+    source: &crate::BinarySource,
+    endian: Endian,
+    table_seed: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for archive_path in &archive_files(source)? {
+        let archive_file = archive::read_archive_file(archive_path)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+        let (key, message_table) = decode::read_message_table(&archive_file, endian, table_seed)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+
+        println!("{}:", archive_path.display());
+        println!("  Messages: {}", message_table.len());
+        println!("  Key: 0x{:04X}", key);
+        println!("  Size: {} bytes", archive_file.len());
+
+        let lengths = message_table.iter().map(|entry| entry.length);
+        if let (Some(min), Some(max)) = (lengths.clone().min(), lengths.max()) {
+            println!("  Message length: min {} / max {} codes", min, max);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the index, offset, length in codes and bytes, and the first and last decrypted code of
+/// every message, as aligned columns below each archive or as one JSON array of all of them.
+/// Messages are read and decrypted, but not decoded to text.
+pub fn print_message_table(
+    source: &crate::BinarySource,
+    endian: Endian,
+    crypto: &CryptoParams,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows = Vec::new();
+    for archive_path in &archive_files(source)? {
+        let archive_file = archive::read_archive_file(archive_path)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+        let (_, messages) = decode::read_decrypted_messages(&archive_file, endian, crypto)
+            .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+
+        let archive_rows = messages
+            .into_iter()
+            .enumerate()
+            .map(|(index, (entry, codes))| MessageRow {
+                archive: archive_path.display().to_string(),
+                index,
+                offset: entry.offset,
+                length: entry.length,
+                bytes: entry.length * 2,
+                first_code: codes.first().map(|code| format!("{:04X}", code)),
+                last_code: codes.last().map(|code| format!("{:04X}", code)),
+            });
+
+        if json {
+            rows.extend(archive_rows);
+            continue;
+        }
+
+        println!("{}:", archive_path.display());
+        println!(
+            "  {:>5}  {:>10}  {:>6}  {:>6}  {:>5}  {:>5}",
+            "Index", "Offset", "Codes", "Bytes", "First", "Last"
+        );
+        for row in archive_rows {
+            println!(
+                "  {:>5}  0x{:08X}  {:>6}  {:>6}  {:>5}  {:>5}",
+                row.index,
+                row.offset,
+                row.length,
+                row.bytes,
+                row.first_code.as_deref().unwrap_or("-"),
+                row.last_code.as_deref().unwrap_or("-")
+            );
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    }
+
+    Ok(())
+}
+
+/// Files given with `-b`, or the files of the `-a` directory sorted by name, without its
+/// subdirectories and hidden files.
+fn archive_files(
+    source: &crate::BinarySource,
+) -> Result<Vec<std::path::PathBuf>, Box<dyn std::error::Error>> {
+    if let Some(files) = &source.archive {
+        Ok(files.clone())
+    } else if let Some(dir) = &source.archive_dir {
+        Ok(archive::read_dir_sorted(
+            dir,
+            &archive::FileFilter::default(),
+            false,
+            false,
+        )?)
+    } else {
+        Err("No archive source specified".into())
+    }
+}
@@ -1,10 +1,32 @@
+pub mod archive;
 pub mod charmap;
+pub mod crypto;
+pub mod csv;
 pub mod decode;
+pub mod diff;
+pub mod dump;
 pub mod encode;
+pub mod error;
+pub mod info;
+pub mod log;
+#[cfg(all(feature = "mmap", unix))]
+pub mod mmap;
+pub mod raw;
+pub mod scaffold;
+pub mod stats;
+pub mod verify;
 
+pub use archive::{Endian, MessageTableEntry, TABLE_ENTRY_SIZE};
 pub use charmap::{Charmap, get_default_charmap};
-pub use decode::{TextArchive, decode_archive};
-pub use encode::{DiagnosticContext, ErrorFormat, validate_message};
+pub use decode::{
+    TextArchive, decode_archive, decode_archive_reader, decode_messages_iter, decode_raw_message,
+    decode_to_json, read_message_table, recover_key,
+};
+pub use encode::{
+    Align, DiagnosticContext, EncodeOptions, ErrorFormat, HashAlgo, OnUnknown, WarningKind,
+    encode_archive, json_languages, validate_message,
+};
+pub use error::ChatotError;
 
 // Define common types used across modules
 use std::path::PathBuf;
@@ -13,18 +35,112 @@ use std::path::PathBuf;
 pub struct BinarySource {
     pub archive: Option<Vec<PathBuf>>,
     pub archive_dir: Option<PathBuf>,
+    pub container: Option<PathBuf>,
 }
 
 #[derive(Clone)]
 pub struct TextSource {
     pub txt: Option<Vec<PathBuf>>,
     pub text_dir: Option<PathBuf>,
+    pub stdin: bool,
+}
+
+#[derive(Clone)]
+pub struct TextDestination {
+    pub txt: Option<Vec<PathBuf>>,
+    pub text_dir: Option<PathBuf>,
+    pub stdout: bool,
+    pub combine: Option<PathBuf>,
 }
 
 #[derive(Clone)]
 pub struct Settings {
     pub json: bool,
+    pub csv: bool,
     pub lang: String,
+    pub base_lang: Vec<String>,
+    pub ordered_by_id: bool,
+    pub multi_join: String,
     pub newer_only: bool,
+    pub force: bool,
+    pub sync_mtime: bool,
     pub msgenc_format: bool,
+    pub all_langs: bool,
+    pub keep_crlf: bool,
+    pub message_separator: Option<String>,
+    pub message_markers: bool,
+    pub annotate: bool,
+    pub expand_trainer_names: bool,
+    pub endian: archive::Endian,
+    pub encoding: archive::TextEncoding,
+    pub dry_run: bool,
+    pub stream: bool,
+    pub stats: bool,
+    pub progress: bool,
+    pub threads: Option<usize>,
+    pub print_hash: bool,
+    pub hash_algo: encode::HashAlgo,
+    pub on_unknown: encode::OnUnknown,
+    pub no_encrypt: bool,
+    pub strict: bool,
+    pub ext: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub recursive: bool,
+    pub name_pattern: Option<String>,
+    pub brute_key: bool,
+    pub hexdump: bool,
+    pub warnings_json: bool,
+    pub warnings_file: Option<PathBuf>,
+    pub limit: Option<usize>,
+    pub align: Option<encode::Align>,
+    pub dedup: bool,
+}
+
+/// Same settings as the command line without any flags.
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            json: false,
+            csv: false,
+            lang: String::from("en_US"),
+            base_lang: vec![String::from("en_US")],
+            ordered_by_id: false,
+            multi_join: String::new(),
+            newer_only: false,
+            force: false,
+            sync_mtime: false,
+            msgenc_format: false,
+            all_langs: false,
+            keep_crlf: false,
+            message_separator: None,
+            message_markers: false,
+            annotate: false,
+            expand_trainer_names: false,
+            endian: archive::Endian::default(),
+            encoding: archive::TextEncoding::default(),
+            dry_run: false,
+            stream: false,
+            stats: false,
+            progress: false,
+            threads: None,
+            print_hash: false,
+            hash_algo: encode::HashAlgo::default(),
+            on_unknown: encode::OnUnknown::default(),
+            no_encrypt: false,
+            strict: false,
+            ext: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            recursive: false,
+            name_pattern: None,
+            brute_key: false,
+            hexdump: false,
+            warnings_json: false,
+            warnings_file: None,
+            limit: None,
+            align: None,
+            dedup: false,
+        }
+    }
 }
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much is printed besides the actual output of a command and errors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Nothing but errors
+    Quiet,
+    /// Warnings and summaries
+    #[default]
+    Normal,
+    /// Every file as it is processed as well
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Normal as u8);
+
+/// Set the level for the whole process, usually once from `-q`/`-v` before any work is done.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Quiet,
+        1 => LogLevel::Normal,
+        _ => LogLevel::Verbose,
+    }
+}
+
+/// Print a line to stdout unless `--quiet` is set.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::level() >= $crate::log::LogLevel::Normal {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Print a line to stderr unless `--quiet` is set, for reports that must stay out of output
+/// written to stdout, like the text of `decode --stdout`.
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if $crate::log::level() >= $crate::log::LogLevel::Normal {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Print a line to stdout only with `--verbose`.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::level() >= $crate::log::LogLevel::Verbose {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Print a line prefixed with "Warning: " to stderr unless `--quiet` is set.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::level() >= $crate::log::LogLevel::Normal {
+            eprintln!("Warning: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {info, note, verbose, warning};
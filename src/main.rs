@@ -1,16 +1,46 @@
 use std::path::PathBuf;
 
 use clap::error::ErrorKind;
-use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+mod archive;
 mod charmap;
+mod crypto;
+mod csv;
 mod decode;
+mod diff;
+mod dump;
 mod encode;
+mod error;
+mod info;
+mod log;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+mod raw;
+mod scaffold;
+mod stats;
+mod verify;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     commands: Commands,
+    /// Fail if the character map has codes that can't be decoded and encoded again
+    #[arg(long, global = true, default_value_t = false)]
+    strict_charmap: bool,
+    /// Print nothing but errors and the output of the command
+    #[arg(
+        short = 'q',
+        long,
+        global = true,
+        default_value_t = false,
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+    /// Print every file as it is processed
+    #[arg(short = 'v', long, global = true, default_value_t = false)]
+    verbose: bool,
 }
 #[derive(Subcommand)]
 enum Commands {
@@ -22,7 +52,7 @@ enum Commands {
         #[command(flatten)]
         source: BinarySource,
         #[command(flatten)]
-        destination: TextSource,
+        destination: TextDestination,
         #[command(flatten)]
         settings: Settings,
     },
@@ -38,7 +68,107 @@ enum Commands {
         #[command(flatten)]
         settings: Settings,
     },
-    ///
+    /// Decode and re-encode binary text archives and report any byte differences
+    Verify {
+        /// Path to custom character map file
+        #[arg(short = 'm', long)]
+        charmap: PathBuf,
+        #[command(flatten)]
+        source: BinarySource,
+        /// Byte order of the archive data
+        #[arg(long, value_enum, default_value_t = archive::Endian::Little)]
+        endian: archive::Endian,
+    },
+    /// Decode two binary text archives and report messages that were added, removed or changed
+    Diff {
+        /// Path to custom character map file
+        #[arg(short = 'm', long)]
+        charmap: PathBuf,
+        /// Path to the original binary text archive
+        #[arg(long)]
+        old: PathBuf,
+        /// Path to the changed binary text archive
+        #[arg(long)]
+        new: PathBuf,
+        /// Byte order of the archive data
+        #[arg(long, value_enum, default_value_t = archive::Endian::Little)]
+        endian: archive::Endian,
+    },
+    /// Decrypt and decode the data of a single message that has no archive header or table
+    DecodeRaw {
+        /// Path to custom character map file
+        #[arg(short = 'm', long)]
+        charmap: PathBuf,
+        /// Path to the file holding the message data
+        #[arg(long)]
+        data: PathBuf,
+        /// Index of the message in its archive, counting from 0, which the decryption depends on
+        #[arg(long)]
+        index: u16,
+        /// Byte offset of the message data in the file
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// Length of the message in codes, by default the rest of the file
+        #[arg(long)]
+        length: Option<usize>,
+        /// Use same format as tool "msgenc" for decoded commands
+        #[arg(long = "msgenc", default_value_t = false)]
+        msgenc_format: bool,
+        /// Byte order of the message data
+        #[arg(long, value_enum, default_value_t = archive::Endian::Little)]
+        endian: archive::Endian,
+    },
+    /// Print message count, key and size of binary text archives without decoding them
+    Info {
+        /// Path to custom character map file, only needed for non-default encryption constants
+        #[arg(short = 'm', long)]
+        charmap: Option<PathBuf>,
+        #[command(flatten)]
+        source: BinarySource,
+        /// Byte order of the archive data
+        #[arg(long, value_enum, default_value_t = archive::Endian::Little)]
+        endian: archive::Endian,
+        /// List the offset, length and first and last code of every message
+        #[arg(long, default_value_t = false)]
+        tabular: bool,
+        /// Print the message list as a JSON array instead of columns
+        #[arg(long, default_value_t = false, requires = "tabular")]
+        json: bool,
+    },
+    /// Report characters, aliases and commands in text files that the charmap can't encode
+    Stats {
+        /// Path to custom character map file
+        #[arg(short = 'm', long)]
+        charmap: PathBuf,
+        #[command(flatten)]
+        source: TextSource,
+        #[command(flatten)]
+        settings: Settings,
+    },
+    /// Print the character map as it is used after resolving aliases, as JSON sorted by code
+    DumpCharmap {
+        /// Path to custom character map file
+        #[arg(short = 'm', long)]
+        charmap: PathBuf,
+    },
+    /// Print the commands of the character map sorted by code, to look up their names
+    #[command(name = "list-commands")]
+    CommandList {
+        /// Path to custom character map file
+        #[arg(short = 'm', long)]
+        charmap: PathBuf,
+        /// Only list commands whose name contains this text, ignoring case
+        filter: Option<String>,
+    },
+    /// Print a minimal text file in the layout that encode expects, to start from
+    Scaffold {
+        /// Print a JSON text file instead of a plain text file
+        #[arg(short = 'j', long, default_value_t = false)]
+        json: bool,
+        /// Language code of the messages in the JSON text file
+        #[arg(short='l', long, default_value_t = String::from("en_US"), requires = "json")]
+        lang: String,
+    },
     Format {
         /// Path to custom character map file
         #[arg(short = 'm', long)]
@@ -59,6 +189,9 @@ pub struct BinarySource {
     /// Directory for archives
     #[arg(short = 'a', long, conflicts_with = "archive")]
     pub archive_dir: Option<std::path::PathBuf>,
+    /// Single file packing several archives behind an index of offsets (experimental)
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["archive", "archive_dir"])]
+    pub container: Option<std::path::PathBuf>,
 }
 
 #[derive(Args, Clone)]
@@ -70,6 +203,26 @@ pub struct TextSource {
     /// Directory for text files
     #[arg(short = 'd', long, conflicts_with = "txt")]
     pub text_dir: Option<std::path::PathBuf>,
+    /// Read the text of a single file from standard input
+    #[arg(long, visible_alias = "input-stdin", default_value_t = false)]
+    pub stdin: bool,
+}
+
+#[derive(Args, Clone)]
+#[group(required = true, multiple = false)]
+pub struct TextDestination {
+    /// Path(s) to the text file(s)
+    #[arg(short='t', long, num_args = 1.., conflicts_with = "text_dir")]
+    pub txt: Option<Vec<std::path::PathBuf>>,
+    /// Directory for text files
+    #[arg(short = 'd', long, conflicts_with = "txt")]
+    pub text_dir: Option<std::path::PathBuf>,
+    /// Print the decoded text of a single archive to standard output instead of writing files
+    #[arg(long, default_value_t = false)]
+    pub stdout: bool,
+    /// Write the decoded text of all archives into one file, each under a "=== name ===" line
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["txt", "text_dir", "stdout", "json", "csv", "hexdump"])]
+    pub combine: Option<std::path::PathBuf>,
 }
 
 #[derive(Args, Clone)]
@@ -77,19 +230,209 @@ pub struct Settings {
     /// Read from JSON format
     #[arg(short = 'j', long, default_value_t = false)]
     json: bool,
+    /// Read and write CSV with an index and a message column, for editing in spreadsheets
+    #[arg(long, default_value_t = false, conflicts_with_all = ["json", "msgenc_format", "annotate", "expand_trainer_names", "limit"])]
+    pub csv: bool,
     /// Language code for JSON input
     #[arg(short='l', long, default_value_t = String::from("en_US"), requires = "json")]
     lang: String,
-    /// Process only files newer than existing outputs, also updates timestamps on source files
+    /// Comma separated languages tried in order for JSON messages without text in --lang
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "en_US",
+        requires = "json"
+    )]
+    pub base_lang: Vec<String>,
+    /// Place JSON messages at the index given by the number at the end of their id, filling gaps with empty messages
+    #[arg(long, default_value_t = false, requires = "json")]
+    pub ordered_by_id: bool,
+    /// Text placed between the lines of JSON messages given as a list, like "\n" or "{CLEAR, 0}"
+    #[arg(long, default_value_t = String::new(), requires = "json")]
+    pub multi_join: String,
+    /// Process only files newer than existing outputs
     #[arg(short = 'n', long = "newer", default_value_t = false)]
     pub newer_only: bool,
+    /// Always process every file, even with --newer when the output is up to date
+    #[arg(short = 'f', long, default_value_t = false)]
+    pub force: bool,
+    /// Set the modified time of each source file to that of its written output
+    #[arg(long, default_value_t = false)]
+    pub sync_mtime: bool,
     /// Use same format as tool "msgenc" for encoding messages
     #[arg(long = "msgenc", default_value_t = false, conflicts_with = "json")]
     pub msgenc_format: bool,
+    /// Decode archives named like name.<lang> into one JSON file per name with all languages
+    #[arg(long, default_value_t = false, requires = "json")]
+    pub all_langs: bool,
+    /// Write a "// [NNN]" comment with the message index before each decoded message
+    #[arg(long, default_value_t = false, conflicts_with_all = ["json", "msgenc_format"])]
+    pub annotate: bool,
+    /// Write trainer names as "// Trainer name:" lines before their message, leaving {TRAINER_NAME} in the message
+    #[arg(long, default_value_t = false, conflicts_with_all = ["json", "msgenc_format"])]
+    pub expand_trainer_names: bool,
+    /// Separate messages in text files with CRLF only, keeping any bare CR or LF inside messages
+    #[arg(long, default_value_t = false, conflicts_with = "json")]
+    pub keep_crlf: bool,
+    /// End every line of text files with this instead of LF, "\n", "\r" and "\t" are unescaped
+    #[arg(long, value_name = "SEP", value_parser = encode::parse_separator, conflicts_with_all = ["json", "csv", "keep_crlf"])]
+    pub message_separator: Option<String>,
+    /// Start every message in text files with a "--- message N ---" line, so messages can span several lines
+    #[arg(long, default_value_t = false, conflicts_with_all = ["json", "csv", "message_separator", "annotate", "expand_trainer_names", "limit"])]
+    pub message_markers: bool,
+    /// Byte order of the archive data
+    #[arg(long, value_enum, default_value_t = archive::Endian::Little)]
+    pub endian: archive::Endian,
+    /// Character encoding of plain text and CSV files
+    #[arg(long, value_parser = archive::TextEncodingParser, default_value = "utf-8", conflicts_with = "json")]
+    pub encoding: archive::TextEncoding,
+    /// Do all the work and report errors, but don't write or touch any files
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+    /// Read archives message by message instead of loading whole files into memory
+    #[arg(long, default_value_t = false)]
+    pub stream: bool,
+    /// Number of worker threads, 0 picks one per CPU core like the default
+    #[arg(long)]
+    pub threads: Option<usize>,
+    /// Print the number of files, messages and archive bytes and the time taken after a batch
+    #[arg(long, default_value_t = false)]
+    pub stats: bool,
+    /// Show a progress bar with the number of processed files
+    #[arg(long, default_value_t = false)]
+    pub progress: bool,
+    /// Print a hash of each written archive
+    #[arg(long, default_value_t = false)]
+    pub print_hash: bool,
+    /// Hash algorithm used by --print-hash
+    #[arg(long, value_enum, default_value_t = encode::HashAlgo::Crc32, requires = "print_hash")]
+    pub hash_algo: encode::HashAlgo,
+    /// How to handle text that can't be encoded
+    #[arg(long, value_enum, default_value_t = encode::OnUnknown::Null)]
+    pub on_unknown: encode::OnUnknown,
+    /// Write and read archives without encryption, for inspecting codes in a hex editor
+    #[arg(long, default_value_t = false)]
+    pub no_encrypt: bool,
+    /// Pad the data of each encoded message to a multiple of N bytes
+    #[arg(long, value_enum, value_name = "N")]
+    pub align: Option<encode::Align>,
+    /// Experimental: store messages whose encrypted codes repeat an earlier message only once
+    #[arg(long, default_value_t = false)]
+    pub dedup: bool,
+    /// Fail instead of warning when an archive has data after its last message or a source
+    /// directory has no files
+    #[arg(long, default_value_t = false)]
+    pub strict: bool,
+    /// Read only files with this extension from a source directory, like `bin` or `txt`
+    #[arg(long, value_name = "EXT")]
+    pub ext: Option<String>,
+    /// Read only files from a source directory whose name matches one of these patterns, with * and ? wildcards
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+    /// Leave out files from a source directory whose name matches any of these patterns
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+    /// Also read the subdirectories of a source directory and mirror them in the destination
+    #[arg(short = 'r', long, default_value_t = false)]
+    pub recursive: bool,
+    /// Name the files written to a destination directory after this, with {stem}, {ext}, {lang} and {index} placeholders
+    #[arg(long, value_name = "PATTERN", value_parser = archive::parse_name_pattern, conflicts_with = "all_langs")]
+    pub name_pattern: Option<String>,
+    /// Try every key on archives whose stored key gives an invalid table or mostly unknown codes
+    #[arg(long, default_value_t = false, conflicts_with = "stream")]
+    pub brute_key: bool,
+    /// Also write the stored and decrypted codes of every message to a .hex file next to the text
+    #[arg(long, default_value_t = false, conflicts_with = "all_langs")]
+    pub hexdump: bool,
+    /// Print warnings about encoded text as a JSON array once all files are encoded
+    #[arg(long, default_value_t = false)]
+    pub warnings_json: bool,
+    /// Write the JSON warnings to this file instead of stderr, implies --warnings-json
+    #[arg(long, value_name = "PATH")]
+    pub warnings_file: Option<PathBuf>,
+    /// Decode only the first N messages of each archive
+    #[arg(long, value_name = "N", conflicts_with = "json")]
+    pub limit: Option<usize>,
+}
+
+/// Settings only decode reads, which the other commands sharing [`Settings`] would ignore.
+const DECODE_ONLY: [&str; 7] = [
+    "all_langs",
+    "annotate",
+    "expand_trainer_names",
+    "stream",
+    "brute_key",
+    "hexdump",
+    "limit",
+];
+
+/// Settings only encode reads, which decode would ignore.
+const ENCODE_ONLY: [&str; 10] = [
+    "base_lang",
+    "ordered_by_id",
+    "multi_join",
+    "print_hash",
+    "hash_algo",
+    "on_unknown",
+    "align",
+    "dedup",
+    "warnings_json",
+    "warnings_file",
+];
+
+/// Exit with a usage error if any of the settings `ids` was given to subcommand `command`,
+/// which doesn't read them, instead of silently ignoring them.
+fn reject_settings(matches: &ArgMatches, command: &str, ids: &[&str], used_by: &str) {
+    let Some(matches) = matches.subcommand_matches(command) else {
+        return;
+    };
+    let given: Vec<String> = ids
+        .iter()
+        .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        .map(|id| format!("--{}", id.replace('_', "-")))
+        .collect();
+    if !given.is_empty() {
+        let mut cmd = Cli::command();
+        cmd.error(
+            ErrorKind::ArgumentConflict,
+            format!("{} can only be used with {}", given.join(", "), used_by),
+        )
+        .exit();
+    }
+}
+
+/// Same settings as the command line without any flags, for tests shared with the library.
+#[cfg(test)]
+impl Default for Settings {
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct SettingsOnly {
+            #[command(flatten)]
+            settings: Settings,
+        }
+        SettingsOnly::parse_from(["chatot"]).settings
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    log::set_level(if cli.quiet {
+        log::LogLevel::Quiet
+    } else if cli.verbose {
+        log::LogLevel::Verbose
+    } else {
+        log::LogLevel::Normal
+    });
+
+    let read_charmap = |path: &PathBuf| {
+        if cli.strict_charmap {
+            charmap::read_charmap_strict(path)
+        } else {
+            charmap::read_charmap(path)
+        }
+    };
 
     match &cli.commands {
         Commands::Decode {
@@ -108,7 +451,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .exit();
             }
 
-            let charmap = charmap::read_charmap(charmap)?;
+            // Only a single archive can be printed to stdout
+            if destination.stdout
+                && (source.archive_dir.is_some()
+                    || source.archive.as_ref().is_some_and(|files| files.len() > 1))
+            {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Cannot print more than one archive to stdout",
+                )
+                .exit();
+            }
+
+            reject_settings(&matches, "decode", &ENCODE_ONLY, "encode");
+
+            // Archives in a container have no files of their own to compare or dump
+            if source.container.is_some()
+                && (settings.hexdump
+                    || settings.all_langs
+                    || settings.newer_only
+                    || settings.sync_mtime)
+            {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Cannot use --hexdump, --all-langs, --newer or --sync-mtime with a container",
+                )
+                .exit();
+            }
+
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
 
             decode::decode_archives(&charmap, source, destination, settings)
         }
@@ -128,10 +501,137 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .exit();
             }
 
-            let charmap = charmap::read_charmap(charmap)?;
+            // Text from stdin can only be written to a single archive
+            if source.stdin
+                && (destination.archive_dir.is_some()
+                    || destination
+                        .archive
+                        .as_ref()
+                        .is_some_and(|files| files.len() > 1))
+            {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Cannot encode text from stdin to more than one archive",
+                )
+                .exit();
+            }
+
+            reject_settings(&matches, "encode", &DECODE_ONLY, "decode");
+
+            if destination.container.is_some() && (settings.newer_only || settings.sync_mtime) {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Cannot use --newer or --sync-mtime with a container",
+                )
+                .exit();
+            }
+
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
 
             encode::encode_texts(&charmap, source, destination, settings)
         }
+        Commands::Verify {
+            charmap,
+            source,
+            endian,
+        } => {
+            if source.container.is_some() {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Containers can only be read by decode",
+                )
+                .exit();
+            }
+
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
+
+            verify::verify_archives(&charmap, source, *endian)
+        }
+        Commands::Diff {
+            charmap,
+            old,
+            new,
+            endian,
+        } => {
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
+
+            diff::print_archive_diff(&charmap, old, new, *endian)
+        }
+        Commands::DecodeRaw {
+            charmap,
+            data,
+            index,
+            offset,
+            length,
+            msgenc_format,
+            endian,
+        } => {
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
+
+            raw::print_raw_message(
+                &charmap,
+                data,
+                *index,
+                *offset,
+                *length,
+                *msgenc_format,
+                *endian,
+            )
+        }
+        Commands::Info {
+            charmap,
+            source,
+            endian,
+            tabular,
+            json,
+        } => {
+            if source.container.is_some() {
+                let mut cmd = Cli::command();
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Containers can only be read by decode",
+                )
+                .exit();
+            }
+
+            // The charmap is only needed for custom encryption constants
+            let crypto = match charmap {
+                Some(charmap) => read_charmap(charmap).map_err(|e| e.to_string())?.crypto,
+                None => crypto::CryptoParams::default(),
+            };
+
+            if *tabular {
+                info::print_message_table(source, *endian, &crypto, *json)
+            } else {
+                info::print_archive_info(source, *endian, crypto.table_seed)
+            }
+        }
+        Commands::Stats {
+            charmap,
+            source,
+            settings,
+        } => {
+            reject_settings(&matches, "stats", &DECODE_ONLY, "decode");
+
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
+
+            stats::print_coverage_report(&charmap, source, settings)
+        }
+        Commands::DumpCharmap { charmap } => {
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
+
+            dump::print_charmap_dump(&charmap)
+        }
+        Commands::CommandList { charmap, filter } => {
+            let charmap = read_charmap(charmap).map_err(|e| e.to_string())?;
+
+            dump::print_commands(&charmap, filter.as_deref());
+            Ok(())
+        }
+        Commands::Scaffold { json, lang } => scaffold::print_scaffold(*json, lang),
         Commands::Format {
             charmap: _charmap,
             source: _source,
@@ -0,0 +1,68 @@
+//! Read-only memory mapping of archive files, built with the `mmap` feature on Unix.
+
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// An archive file mapped into memory, dereferencing to its bytes. The file must not be
+/// truncated while it is mapped, reading the missing pages stops the process with `SIGBUS`.
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl MappedFile {
+    /// Map the whole file at `path`. An empty file can't be mapped and gives an empty slice.
+    pub fn open(path: &Path) -> std::io::Result<MappedFile> {
+        let file = std::fs::File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "File is too large to map")
+        })?;
+        if len == 0 {
+            return Ok(MappedFile {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+
+        // SAFETY: a new private read-only mapping of an open file, which stays valid after the
+        // file is closed. No Rust object refers to the mapped range yet.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(MappedFile { ptr, len })
+    }
+}
+
+impl std::ops::Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `ptr` points to `len` readable bytes until the mapping is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr.cast::<u8>(), self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: the range was mapped in `open` and the borrow checker ensures no slice of
+            // it outlives `self`
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+
+use crate::{
+    archive::{self, Endian},
+    charmap, decode,
+};
+
+/// Decode message data that was cut out of an archive, starting `offset` bytes into the file and
+/// running for `length` codes or up to the end of the file, and print it.
+pub fn print_raw_message(
+    charmap: &charmap::Charmap,
+    data_path: &Path,
+    index: u16,
+    offset: u64,
+    length: Option<usize>,
+    msgenc_format: bool,
+    endian: Endian,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = archive::read_archive_file(data_path)
+        .map_err(|e| format!("Failed to read message data {:?}: {}", data_path, e))?;
+
+    let (start, end) = message_bounds(data.len(), offset, length, data_path)?;
+
+    let message =
+        decode::decode_raw_message(charmap, &data[start..end], index, msgenc_format, endian)
+            .map_err(|e| format!("Failed to decode message data {:?}: {}", data_path, e))?;
+    println!("{}", message);
+
+    Ok(())
+}
+
+/// Byte range of the message data starting `offset` bytes into data of `data_len` bytes and
+/// running for `length` codes or up to the end. Lengths so large that the range overflows are
+/// past the end like any other.
+fn message_bounds(
+    data_len: usize,
+    offset: u64,
+    length: Option<usize>,
+    data_path: &Path,
+) -> Result<(usize, usize), String> {
+    let start = usize::try_from(offset)
+        .ok()
+        .filter(|&start| start <= data_len)
+        .ok_or_else(|| {
+            format!(
+                "Offset 0x{:X} is past the end of {:?} ({} bytes)",
+                offset, data_path, data_len
+            )
+        })?;
+    let end = match length {
+        Some(length) => length
+            .checked_mul(2)
+            .and_then(|bytes| start.checked_add(bytes))
+            .filter(|&end| end <= data_len)
+            .ok_or_else(|| {
+                format!(
+                    "{} codes from offset 0x{:X} run past the end of {:?} ({} bytes)",
+                    length, offset, data_path, data_len
+                )
+            })?,
+        None => data_len,
+    };
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_bounds_cover_length_or_rest_of_data() {
+        let path = Path::new("data.bin");
+        assert_eq!(message_bounds(10, 2, Some(3), path), Ok((2, 8)));
+        assert_eq!(message_bounds(10, 2, None, path), Ok((2, 10)));
+        assert_eq!(message_bounds(10, 10, Some(0), path), Ok((10, 10)));
+    }
+
+    #[test]
+    fn message_bounds_reject_data_past_the_end() {
+        let path = Path::new("data.bin");
+        assert!(message_bounds(10, 11, None, path).is_err());
+        assert!(message_bounds(10, 2, Some(5), path).is_err());
+    }
+
+    #[test]
+    fn message_bounds_reject_overflowing_lengths() {
+        let path = Path::new("data.bin");
+        let error = message_bounds(10, 0, Some(usize::MAX / 2 + 1), path).unwrap_err();
+        assert!(error.contains("run past the end"), "{error}");
+        assert!(message_bounds(10, 2, Some(usize::MAX / 2), path).is_err());
+    }
+}
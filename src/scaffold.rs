@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+
+use crate::encode::{JsonInput, JsonMessage, MessageContent};
+
+/// Print a minimal text file that can be encoded as is, as JSON with the text in language
+/// `lang` or as a plain text file with its key comment.
+pub fn print_scaffold(json: bool, lang: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let samples = [
+        MessageContent::Single(String::from("Hello World!")),
+        MessageContent::Multi(vec![
+            String::from("A message given as a list of lines, "),
+            String::from("which are joined when encoding."),
+        ]),
+    ];
+
+    if !json {
+        // Every line after the key comment is one message
+        println!("// Key: 0x0000");
+        for sample in &samples {
+            match sample {
+                MessageContent::Single(text) => println!("{}", text),
+                MessageContent::Multi(lines) => println!("{}", lines.join("")),
+            }
+        }
+        return Ok(());
+    }
+
+    let template = JsonInput {
+        archive: None,
+        key: 0,
+        messages: samples
+            .into_iter()
+            .enumerate()
+            .map(|(index, content)| JsonMessage {
+                id: format!("msg_sample_{:05}", index),
+                lang_message: HashMap::from([(lang.to_string(), content)]),
+            })
+            .collect(),
+    };
+    println!("{}", serde_json::to_string_pretty(&template)?);
+
+    Ok(())
+}
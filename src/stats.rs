@@ -0,0 +1,130 @@
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::{archive, charmap, csv, encode};
+
+/// Text the charmap can't encode, collected over all scanned files.
+#[derive(Default)]
+pub struct CoverageReport {
+    /// Unknown characters, aliases, escapes and commands with the files they appear in
+    pub unknown: BTreeMap<String, UnknownText>,
+}
+
+#[derive(Default)]
+pub struct UnknownText {
+    pub count: usize,
+    pub files: BTreeSet<PathBuf>,
+}
+
+/// Scan text files for anything the charmap can't encode and print every distinct occurrence.
+/// Uses the same parsing as encoding but never writes an archive. Fails if anything is unknown.
+pub fn print_coverage_report(
+    charmap: &charmap::Charmap,
+    source: &crate::TextSource,
+    settings: &crate::Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Get list of text files
+    let text_files = if source.stdin {
+        vec![PathBuf::from("<stdin>")]
+    } else if let Some(files) = &source.txt {
+        files.clone()
+    } else if let Some(dir) = &source.text_dir {
+        archive::read_dir_sorted(
+            dir,
+            &archive::FileFilter::from_settings(settings),
+            settings.recursive,
+            settings.strict,
+        )?
+    } else {
+        return Err("No text source specified".into());
+    };
+
+    let results: Vec<Result<Vec<String>, String>> = text_files
+        .par_iter()
+        .map(|text_path| {
+            let text_content = if source.stdin {
+                let mut text_bytes = Vec::new();
+                std::io::stdin().read_to_end(&mut text_bytes).and_then(|_| {
+                    settings
+                        .encoding
+                        .decode(&text_bytes)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })
+            } else {
+                archive::read_text_file(text_path, settings.encoding)
+            }
+            .map_err(|e| format!("Failed to read text {:?}: {}", text_path, e))?;
+            let messages: Vec<String> = if settings.json {
+                encode::parse_json_archives(
+                    &text_content,
+                    &encode::JsonMessageOptions::from_settings(settings),
+                )
+                .map_err(|e| format!("Failed to read JSON {:?}: {}", text_path, e))?
+                .into_iter()
+                .flat_map(|archive| archive.messages)
+                .collect()
+            } else if settings.csv {
+                csv::parse_csv_messages(&text_content)
+                    .map_err(|e| format!("Failed to read CSV {:?}: {}", text_path, e))?
+                    .1
+            } else {
+                encode::parse_text_messages(
+                    &text_content,
+                    encode::TextLayout::from_settings(settings),
+                )
+                .map_err(|e| format!("Failed to read text {:?}: {}", text_path, e))?
+                .messages
+            };
+
+            // JSON files always use the regular command format, like when encoding
+            let msgenc_format = settings.msgenc_format && !settings.json;
+            Ok(messages
+                .iter()
+                .flat_map(|message| {
+                    encode::message_warnings(charmap, message, msgenc_format, Some(text_path))
+                })
+                .map(|warning| warning.source[warning.span].to_string())
+                .collect())
+        })
+        .collect();
+
+    let mut report = CoverageReport::default();
+    for (text_path, result) in text_files.iter().zip(results) {
+        for text in result? {
+            let unknown = report.unknown.entry(text).or_default();
+            unknown.count += 1;
+            unknown.files.insert(text_path.clone());
+        }
+    }
+
+    if report.unknown.is_empty() {
+        println!("All text in {} file(s) can be encoded", text_files.len());
+        return Ok(());
+    }
+
+    // Most frequent first
+    let mut unknown: Vec<_> = report.unknown.iter().collect();
+    unknown.sort_by_key(|(_, occurrences)| std::cmp::Reverse(occurrences.count));
+    for (text, occurrences) in &unknown {
+        let files: Vec<String> = occurrences
+            .files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        println!(
+            "'{}': {} occurrence(s) in {}",
+            text,
+            occurrences.count,
+            files.join(", ")
+        );
+    }
+
+    Err(format!(
+        "Found {} distinct item(s) that can't be encoded in {} file(s)",
+        unknown.len(),
+        text_files.len()
+    )
+    .into())
+}
@@ -0,0 +1,135 @@
+use rayon::prelude::*;
+
+use crate::{
+    archive::{self, Endian},
+    charmap, decode,
+    encode::{self, EncodeOptions, OnUnknown},
+};
+
+/// The part of an archive the first differing byte falls in.
+enum Location {
+    Header,
+    TableEntry(usize),
+    Message(usize),
+    /// Past the last message described by the original table
+    Trailing,
+}
+
+/// Decode each archive, encode the decoded text again and compare the result byte for byte.
+/// Prints one line per archive and fails if any archive does not round-trip.
+pub fn verify_archives(
+    charmap: &charmap::Charmap,
+    source: &crate::BinarySource,
+    endian: Endian,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Get list of archive files
+    let archive_files: Vec<std::path::PathBuf> = if let Some(files) = &source.archive {
+        files.clone()
+    } else if let Some(dir) = &source.archive_dir {
+        archive::read_dir_sorted(dir, &archive::FileFilter::default(), false, false)?
+    } else {
+        return Err("No archive source specified".into());
+    };
+
+    let results: Vec<Result<Option<(usize, Location)>, String>> = archive_files
+        .par_iter()
+        .map(|archive_path| {
+            let archive_file = archive::read_archive_file(archive_path)
+                .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))?;
+            let archive = decode::decode_archive(charmap, &archive_file, false, endian)
+                .map_err(|e| format!("Failed to decode archive {:?}: {}", archive_path, e))?;
+            let opts = EncodeOptions {
+                msgenc_format: false,
+                endian,
+                on_unknown: OnUnknown::Null,
+                align: None,
+                dedup: false,
+            };
+            let encoded = encode::encode_messages(
+                charmap,
+                archive.key,
+                &archive.messages,
+                opts,
+                Some(archive_path),
+            )
+            .map_err(|e| format!("Failed to encode archive {:?}: {}", archive_path, e))?;
+
+            find_mismatch(&archive_file, &encoded, endian, charmap.crypto.table_seed)
+                .map_err(|e| format!("Failed to read archive {:?}: {}", archive_path, e))
+        })
+        .collect();
+
+    let mut failed = 0;
+    for (archive_path, result) in archive_files.iter().zip(results) {
+        match result? {
+            None => println!("{}: OK", archive_path.display()),
+            Some((offset, location)) => {
+                failed += 1;
+                let location = match location {
+                    Location::Header => "header".to_string(),
+                    Location::TableEntry(index) => format!("message table entry {index}"),
+                    Location::Message(index) => format!("message {index}"),
+                    Location::Trailing => "after the last message".to_string(),
+                };
+                println!(
+                    "{}: MISMATCH at byte 0x{:X} ({})",
+                    archive_path.display(),
+                    offset,
+                    location
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} archive(s) did not round-trip",
+            failed,
+            archive_files.len()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn find_mismatch(
+    original: &[u8],
+    encoded: &[u8],
+    endian: Endian,
+    table_seed: u32,
+) -> Result<Option<(usize, Location)>, crate::error::ChatotError> {
+    let Some(offset) = original
+        .iter()
+        .zip(encoded)
+        .position(|(a, b)| a != b)
+        .or_else(|| (original.len() != encoded.len()).then(|| original.len().min(encoded.len())))
+    else {
+        return Ok(None);
+    };
+
+    let header_size = 4;
+    if offset < header_size {
+        return Ok(Some((offset, Location::Header)));
+    }
+
+    // Locate the differing byte using the layout of the original archive
+    let (_, message_table) = decode::read_message_table(original, endian, table_seed)?;
+    let table_end = header_size + message_table.len() * archive::TABLE_ENTRY_SIZE;
+    if offset < table_end {
+        let index = (offset - header_size) / archive::TABLE_ENTRY_SIZE;
+        return Ok(Some((offset, Location::TableEntry(index))));
+    }
+
+    let location = message_table
+        .iter()
+        .position(|entry| {
+            let start = entry.offset as usize;
+            let end = start + entry.length as usize * 2;
+            (start..end).contains(&offset)
+        })
+        .map(Location::Message)
+        .unwrap_or(Location::Trailing);
+
+    Ok(Some((offset, location)))
+}